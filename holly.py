@@ -3,14 +3,15 @@ Client library for writing Holly plugins
 Authored by Jackson Coxson
 """
 
+import codecs
 import json
-import jsonstream
 import socket
 import re
-from typing import Union
+from typing import Optional, Union
 import itertools
 
 DEFAULT_JUNK = ["a", "an", "are", "as", "is", "the"]
+PROTOCOL_VERSION = 2
 
 
 class HollyError(Exception):
@@ -182,6 +183,15 @@ class HollyMessage:
         content: The content of the message.
         chat_id: Identifier of the chat the message belongs to.
         sender: Sender of the message.
+        id: Messenger's id for the message, if known.
+        timestamp: When the message was sent, in Unix milliseconds.
+        from_bot: Whether Holly's own account sent the message.
+        reply_to: The quoted message as a dict with id, sender and content,
+        if this one is a reply.
+        attachments: Dicts with the type, local path, MIME type, size and
+        URL of each attachment.
+        reactions: Dicts with the emoji and sender of each reaction.
+        edited: Whether the message was edited before Holly read it.
     """
 
     def __init__(
@@ -195,10 +205,24 @@ class HollyMessage:
             self.content = json_data["content"]
             self.chat_id = json_data["chat_id"]
             self.sender = json_data["sender"]
+            self.id = json_data.get("id")
+            self.timestamp = json_data.get("timestamp")
+            self.from_bot = json_data.get("from_bot", False)
+            self.reply_to = json_data.get("reply_to")
+            self.attachments = json_data.get("attachments", [])
+            self.reactions = json_data.get("reactions", [])
+            self.edited = json_data.get("edited", False)
         else:
             self.content = content
             self.chat_id = chat_id
             self.sender = sender
+            self.id = None
+            self.timestamp = None
+            self.from_bot = False
+            self.reply_to = None
+            self.attachments = []
+            self.reactions = []
+            self.edited = False
 
     def __str__(self):
         return str(self.to_dict())
@@ -244,28 +268,85 @@ class HollyClient:
         socket: The socket object for communication.
     """
 
-    def __init__(self, host="localhost", port=8011):
+    def __init__(self, host="localhost", port=8011, version=PROTOCOL_VERSION):
         """
         Initializes the HollyClient instance and connects to the server.
 
         Args:
             host (str): The host address of the server. Default is 'localhost'.
             port (int): The port number of the server. Default is 8011.
+            version (int): The protocol version to request. Version 1 is the
+            original bare message protocol.
 
         Raises:
             HollyError: If connection to the server fails.
         """
         self.host = host
         self.port = port
-        self.cache: list[HollyMessage] = []
+        self.version = 1
+        self.buffer = ""
+        self.errors: list[str] = []
+        self.results: dict[str, dict] = {}
+        self.selector_health: Optional[dict] = None
+        self.login_challenge: Optional[dict] = None
+        self.config_reloaded: Optional[dict] = None
+        self.reaction_events: list[dict] = []
+        self.revision_events: list[dict] = []
+        self.utf8 = codecs.getincrementaldecoder("utf-8")()
+        self.json = json.JSONDecoder()
         try:
             self.socket = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
             self.socket.connect((host, port))
         except ConnectionRefusedError as e:
             raise HollyError(f"Connection to server at {host}:{port} refused.") from e
+        if version >= 2:
+            self.command("hello", v=version)
+            welcome = self.recv_event()
+            if welcome.get("type") != "welcome":
+                raise HollyError(f"Expected a welcome from Holly, got {welcome}")
+            self.version = welcome["version"]
+
+    def _next_document(self):
+        """Pops the next complete JSON document off the receive buffer,
+        or returns None if there isn't a whole one yet.
+        """
+        self.buffer = self.buffer.lstrip()
+        if not self.buffer:
+            return None
+        try:
+            msg, end = self.json.raw_decode(self.buffer)
+        except json.JSONDecodeError:
+            return None
+        self.buffer = self.buffer[end:]
+        return msg
+
+    def recv_event(self) -> dict:
+        """Receives the next raw event from the server.
+        On protocol v1 every event is a bare message.
+
+        Raises:
+            HollyError: If there's an issue receiving the event.
+        """
+        while True:
+            event = self._next_document()
+            if event is not None:
+                return event
+            try:
+                data = self.socket.recv(4096)
+            except Exception as e:
+                raise HollyError(f"Failed to receive message: {e}") from e
+            if not data:
+                raise HollyError("Connection closed by Holly.")
+            self.buffer += self.utf8.decode(data)
 
     def recv(self) -> HollyMessage:
-        """Receives a message from the server.
+        """Receives a message from the server, skipping other events.
+        Errors Holly reports about frames we sent are collected in `errors`,
+        and `sent`/`failed` events are kept in `results` by request id.
+        The latest `selector_health`, `login_challenge` and `config_reloaded`
+        events are kept in attributes of the same name, and
+        `reaction_added`/`reaction_removed` events pile up in `reaction_events`
+        and `message_edited`/`message_unsent` ones in `revision_events`.
 
         Returns:
             HollyMessage: The received message.
@@ -273,57 +354,171 @@ class HollyClient:
         Raises:
             HollyError: If there's an issue receiving the message.
         """
-        if len(self.cache) > 0:
-            msg = self.cache.pop(0)
-            return HollyMessage(json_data=msg)
+        while True:
+            event = self.recv_event()
+            if self.version < 2:
+                if "error" in event:
+                    self.errors.append(event["error"])
+                    continue
+                return HollyMessage(json_data=event)
+            if event.get("type") == "error":
+                self.errors.append(event["reason"])
+            elif event.get("type") in ("sent", "failed"):
+                self.results[event["request_id"]] = event
+            elif event.get("type") == "selector_health":
+                self.selector_health = event
+            elif event.get("type") == "login_challenge":
+                self.login_challenge = event
+            elif event.get("type") == "config_reloaded":
+                self.config_reloaded = event
+            elif event.get("type") in ("reaction_added", "reaction_removed"):
+                self.reaction_events.append(event)
+            elif event.get("type") in ("message_edited", "message_unsent"):
+                self.revision_events.append(event)
+            elif event.get("type") == "message":
+                return HollyMessage(json_data=event)
+
+    def _write(self, frame: dict):
         try:
-            data = self.socket.recv(2048)
-            it = jsonstream.loads(data.decode("utf-8"))
-            msg = next(it)
-            if msg:
-                self.cache.extend(list(it))
-                return HollyMessage(json_data=msg)
-            else:
-                raise HollyError("No valid JSON recieved from network.")
-        except json.JSONDecodeError as e:
-            raise HollyError("Failed to decode received message.") from e
+            self.socket.sendall(json.dumps(frame).encode("utf-8") + b"\n")
         except Exception as e:
-            raise HollyError(f"Failed to receive message: {e}") from e
+            raise HollyError(f"Failed to send message: {e}") from e
 
-    def send(self, msg: HollyMessage):
+    def command(self, kind: str, v=None, **fields):
+        """Sends a protocol v2 command to the server.
+
+        Args:
+            kind (str): The command type, such as 'send_message'.
+            v (int): The envelope version. Defaults to the negotiated version.
+            **fields: The command's fields.
+
+        Raises:
+            HollyError: If there's an issue sending the command.
+        """
+        self._write({"v": v or self.version, "type": kind, **fields})
+
+    def send(
+        self,
+        msg: HollyMessage,
+        request_id=None,
+        priority=None,
+        typing=None,
+        split=False,
+        reply_to=None,
+    ):
         """Sends a message to the server.
 
         Args:
             msg (HollyMessage): The message to be sent.
+            request_id (str): Optional id to track delivery with. Holly answers
+            with `ack`, then `sent` or `failed` events carrying it. Needs v2.
+            priority (str): 'low', 'normal' or 'high'. Needs v2.
+            typing (dict): Overrides how this message is typed, like
+            {"mode": "paste"}. Needs v2.
+            split (bool): Send each line of the content as its own message
+            instead of line breaks in one. Needs v2.
+            reply_to (Union[HollyMessage, dict]): A received message to reply
+            to, or a dict with its id, or its content and sender. Needs v2.
 
         Raises:
             HollyError: If there's an issue sending the message.
         """
-        try:
-            self.socket.send(msg.serialize())
-        except Exception as e:
-            raise HollyError(f"Failed to send message: {e}") from e
+        if self.version < 2:
+            self._write(msg.to_dict())
+            return
+        fields = {"chat_id": msg.chat_id, "content": msg.content}
+        if request_id is not None:
+            fields["request_id"] = request_id
+        if priority is not None:
+            fields["priority"] = priority
+        if typing is not None:
+            fields["typing"] = typing
+        if split:
+            fields["split"] = True
+        if isinstance(reply_to, HollyMessage):
+            if reply_to.id is not None:
+                reply_to = {"id": reply_to.id}
+            else:
+                reply_to = {"sender": reply_to.sender, "content": reply_to.content}
+        if reply_to is not None:
+            fields["reply_to"] = reply_to
+        self.command("send_message", **fields)
 
     def close(self):
         """Closes the connection to the server."""
         self.socket.close()
 
+    def _control(self, kind: str, legacy: HollyMessage):
+        if self.version < 2:
+            self.send(legacy)
+        else:
+            self.command(kind)
+
     def screenshot(self):
         """Command Holly core to take a screenshot"""
-        self.send(HollyMessage("", "", "<screenshot>"))
+        self._control("screenshot", HollyMessage("", "", "<screenshot>"))
 
     def html(self):
         """Command Holly core to dump the page HTML"""
-        self.send(HollyMessage("", "", "<html>"))
+        self._control("html", HollyMessage("", "", "<html>"))
 
     def restart(self):
         """Command Holly core to restart"""
-        self.send(HollyMessage("", "", "<restart>"))
+        self._control("restart", HollyMessage("", "", "<restart>"))
 
     def refresh(self):
         """Command Holly core to refresh the page"""
-        self.send(HollyMessage("", "", "<refresh>"))
+        self._control("refresh", HollyMessage("", "", "<refresh>"))
+
+    def reload_selectors(self):
+        """Command Holly core to reread its selectors file. Needs v2."""
+        self.command("reload_selectors")
+
+    def reload_config(self):
+        """Command Holly core to reread its config file. Needs v2."""
+        self.command("reload_config")
 
-    def file(self, path: str, chat_id: str):
+    def react(self, message, emoji: str, chat_id=None, remove=False, request_id=None):
+        """Reacts to a message, or takes the reaction off. Needs v2.
+
+        Args:
+            message (Union[HollyMessage, dict]): A received message, or a dict
+            with its id, or its content and sender.
+            emoji (str): The reaction.
+            chat_id (str): The chat, if message is a dict.
+            remove (bool): Take the reaction off instead.
+            request_id (str): Optional id to track delivery with.
+        """
+        if isinstance(message, HollyMessage):
+            chat_id = message.chat_id
+            if message.id is not None:
+                message = {"id": message.id}
+            else:
+                message = {"sender": message.sender, "content": message.content}
+        fields = {"chat_id": chat_id, "message": message, "emoji": emoji}
+        if remove:
+            fields["remove"] = True
+        if request_id is not None:
+            fields["request_id"] = request_id
+        self.command("react", **fields)
+
+    def set_typing(self, chat_id: str, typing: Optional[dict] = None):
+        """Changes how messages to a chat are typed, or goes back to the
+        config's settings if typing is None. Needs v2."""
+        if typing is None:
+            self.command("set_typing", chat_id=chat_id)
+        else:
+            self.command("set_typing", chat_id=chat_id, typing=typing)
+
+    def login_code(self, code: str):
+        """Answers a two-factor login challenge with a code. Needs v2."""
+        self.command("login_code", code=code)
+
+    def file(self, path: str, chat_id: str, request_id=None):
         """Sends a file into a chat"""
-        self.send(HollyMessage(path, chat_id, "<file>"))
+        if self.version < 2:
+            self.send(HollyMessage(path, chat_id, "<file>"))
+        elif request_id is None:
+            self.command("send_file", chat_id=chat_id, path=path)
+        else:
+            self.command("send_file", chat_id=chat_id, path=path, request_id=request_id)
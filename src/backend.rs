@@ -0,0 +1,139 @@
+// Jackson Coxson
+// The operations the main loop needs from a chat service.
+// `Browser` drives the real Messenger. `MockBackend` is an in-memory stand-in
+// for running the polling, caching and dispatch logic offline.
+
+use thirtyfour::error::WebDriverResult;
+
+use crate::{
+    browser::{Browser, SendError},
+    chat::{ChatMessage, ChatPreview, MessageRef},
+    selectors::{SelectorCheck, Selectors},
+    typing::Style,
+};
+
+pub trait ChatBackend {
+    /// Gets all the chats on the side bar, including whether they are unread
+    async fn get_chats(&self) -> WebDriverResult<Vec<ChatPreview>>;
+
+    /// Opens the chat with the given id
+    async fn go_to_chat(&self, id: &str) -> Result<(), SendError>;
+
+    /// Gets the messages in the current chat, or just the newest one if `last` is set
+    async fn get_messages(&self, last: bool) -> WebDriverResult<Vec<ChatMessage>>;
+
+    /// Starts a reply to a message in the current chat, so the next message sent quotes it
+    async fn start_reply(&self, target: &MessageRef) -> Result<(), SendError>;
+
+    /// Adds or removes our reaction on a message in the current chat
+    async fn react(&self, target: &MessageRef, emoji: &str, remove: bool) -> Result<(), SendError>;
+
+    /// Types and sends a message to the current chat
+    async fn send_message(&self, message: &str, style: &Style) -> Result<(), SendError>;
+
+    /// Sends a file to the current chat
+    async fn send_file(&self, path: &str) -> Result<(), SendError>;
+
+    /// Reloads the page
+    async fn refresh(&self) -> WebDriverResult<()>;
+
+    /// Gets the ID of the current chat
+    async fn get_current_chat(&self) -> WebDriverResult<String>;
+
+    /// Declines an incoming call, if there is one
+    async fn decline_call(&self) -> WebDriverResult<()>;
+
+    /// Saves a screenshot to the logs folder
+    async fn screenshot_log(&self) -> WebDriverResult<()>;
+
+    /// Saves the page HTML to the logs folder
+    async fn html_log(&self) -> WebDriverResult<()>;
+
+    /// Downloads an attachment, returning its bytes and MIME type
+    async fn fetch_media(&self, url: &str, max_size: u64) -> WebDriverResult<(Vec<u8>, String)>;
+
+    /// Swaps in new element selectors. Backends without a page ignore them.
+    fn set_selectors(&self, _selectors: Selectors) {}
+
+    /// Changes how long to wait for the page after clicking around
+    fn set_latency(&self, _latency: usize) {}
+
+    /// Checks the critical selectors against the page. Backends without a page have nothing to check.
+    async fn check_selectors(&self) -> WebDriverResult<Vec<SelectorCheck>> {
+        Ok(Vec::new())
+    }
+}
+
+impl ChatBackend for Browser {
+    async fn get_chats(&self) -> WebDriverResult<Vec<ChatPreview>> {
+        Ok(self
+            .get_chats()
+            .await?
+            .iter()
+            .map(|c| ChatPreview {
+                id: c.id.clone(),
+                unread: c.unread,
+            })
+            .collect())
+    }
+
+    async fn go_to_chat(&self, id: &str) -> Result<(), SendError> {
+        self.go_to_chat(id).await
+    }
+
+    async fn get_messages(&self, last: bool) -> WebDriverResult<Vec<ChatMessage>> {
+        self.get_messages(last).await
+    }
+
+    async fn start_reply(&self, target: &MessageRef) -> Result<(), SendError> {
+        self.start_reply(target).await
+    }
+
+    async fn react(&self, target: &MessageRef, emoji: &str, remove: bool) -> Result<(), SendError> {
+        self.react(target, emoji, remove).await
+    }
+
+    async fn send_message(&self, message: &str, style: &Style) -> Result<(), SendError> {
+        self.send_message(message, style).await
+    }
+
+    async fn send_file(&self, path: &str) -> Result<(), SendError> {
+        self.send_file(path).await
+    }
+
+    async fn refresh(&self) -> WebDriverResult<()> {
+        self.refresh().await
+    }
+
+    async fn get_current_chat(&self) -> WebDriverResult<String> {
+        self.get_current_chat().await
+    }
+
+    async fn decline_call(&self) -> WebDriverResult<()> {
+        self.decline_call().await
+    }
+
+    async fn screenshot_log(&self) -> WebDriverResult<()> {
+        self.screenshot_log().await
+    }
+
+    async fn html_log(&self) -> WebDriverResult<()> {
+        self.html_log().await
+    }
+
+    async fn fetch_media(&self, url: &str, max_size: u64) -> WebDriverResult<(Vec<u8>, String)> {
+        self.fetch_media(url, max_size).await
+    }
+
+    fn set_selectors(&self, selectors: Selectors) {
+        self.set_selectors(selectors)
+    }
+
+    fn set_latency(&self, latency: usize) {
+        self.set_latency(latency)
+    }
+
+    async fn check_selectors(&self) -> WebDriverResult<Vec<SelectorCheck>> {
+        self.check_selectors().await
+    }
+}
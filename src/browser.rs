@@ -1,69 +1,176 @@
 // Jackson Coxson
 
-use std::process::Stdio;
+use std::{
+    fmt::{Display, Formatter},
+    process::Stdio,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
 
-use log::{error, info, warn};
-use rand::Rng;
+use base64::Engine;
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::{Number, Value};
-use thirtyfour::prelude::*;
+use thirtyfour::{
+    common::capabilities::firefox::FirefoxPreferences,
+    cookie::{time::OffsetDateTime, SameSite},
+    prelude::*,
+    ChromeCapabilities, FirefoxCapabilities,
+};
 use tokio::process::{Child, Command};
 
-use crate::config::Config;
+use crate::{
+    chat::{self, ChatMessage, MessageRef},
+    config::{BrowserKind, Config},
+    secrets::{self, Cipher},
+    selectors::{require, Locate, SelectorCheck, Selectors},
+    typing::{self, Style},
+};
 
 const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/87.0.4280.88 Safari/537.36";
 
 pub struct Browser {
     driver: WebDriver,
-    latency: usize,
-    _gecko: Child,
+    latency: AtomicUsize,
+    selectors: Mutex<Arc<Selectors>>,
+    /// Encrypts cookies.json and storage.json
+    cipher: Option<Cipher>,
+    _gecko: Option<Child>,
 }
 
+/// Where a login has got to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginState {
+    LoggedIn,
+    /// Facebook wants something from a person before letting us in
+    Challenge(Challenge),
+    /// Still loading, or on a page we don't recognize
+    Pending,
+}
+
+/// Interstitials Facebook shows between the login form and Messenger
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Challenge {
+    /// A code from an authenticator app or text message
+    TwoFactor,
+    /// A "was this you" or other security check
+    Checkpoint,
+    Captcha,
+}
+
+/// Why a message or file couldn't be delivered
+#[derive(Debug)]
+pub enum SendError {
+    ChatNotFound,
+    InvalidFileFormat,
+    UploadFailed,
+    /// The message to reply or react to isn't in the loaded history
+    MessageNotFound(String),
+    /// A file Holly was asked to load is invalid
+    InvalidConfig(String),
+    /// The command doesn't make sense right now
+    Rejected(String),
+    /// The selectors the command needs are turned off
+    Unsupported(String),
+    WebDriver(WebDriverError),
+}
+
+/// A cookie with every attribute WebDriver gives us.
+/// Files from older versions only have `name` and `value`.
 #[derive(Serialize, Deserialize)]
 struct JsonCookie {
     name: String,
     value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    domain: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    /// Unix timestamp. Session cookies have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expiry: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    secure: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    http_only: Option<bool>,
+    /// "Strict", "Lax" or "None"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    same_site: Option<String>,
 }
 
 impl Browser {
-    pub async fn new(config: &Config) -> Result<Self, WebDriverResult<()>> {
-        let _gecko = launch_driver(&config.gecko.path, config.gecko.port);
-        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-
-        let mut caps = DesiredCapabilities::firefox();
-        caps.add_firefox_arg("--disable-infobars").unwrap();
-        caps.add_firefox_arg("--no-sandbox").unwrap();
-        caps.add_firefox_arg("--disable-application-cache").unwrap();
-        caps.add_firefox_arg("--disable-gpu").unwrap();
-        caps.add_firefox_arg("--disable-dev-shm-usage").unwrap();
-        caps.add_firefox_arg("--start-maximized").unwrap();
-        caps.add_firefox_arg("--disable-extensions").unwrap();
-        caps.add_firefox_arg("--window-size=1920,1080").unwrap();
-        caps.add_firefox_arg(&format!("user-agent={USER_AGENT}"))
-            .unwrap();
+    /// Launches the browser and opens Messenger
+    pub async fn new(config: &Config) -> WebDriverResult<Self> {
+        let browser = Self::launch(config).await?;
+        browser.driver.goto("https://messenger.com").await.unwrap();
+        Ok(browser)
+    }
 
-        if config.gecko.headless {
-            caps.add_firefox_arg("--headless").unwrap();
-            caps.add_firefox_arg("--disable-gpu").unwrap();
-        }
+    /// Launches the browser on a blank page
+    pub async fn launch(config: &Config) -> WebDriverResult<Self> {
+        let cipher = Cipher::from_config(&config.secrets).map_err(WebDriverError::CustomError)?;
 
-        let driver = WebDriver::new("http://localhost:4444", caps).await.unwrap();
+        let _gecko = if config.gecko.spawn {
+            Some(launch_driver(
+                config.browser,
+                config.gecko.driver_path(config.browser),
+                config.gecko.port,
+            ))
+        } else {
+            None
+        };
 
-        driver.goto("https://messenger.com").await.unwrap();
+        let url = config.gecko.url();
+        let driver = match config.browser {
+            BrowserKind::Firefox => connect(&url, firefox_caps(config)?, config).await?,
+            BrowserKind::Chromium => connect(&url, chromium_caps(config)?, config).await?,
+        };
+
+        let selectors = Selectors::load(&config.selectors).unwrap_or_else(|e| {
+            error!("{e}, using the built-in selectors");
+            Selectors::default()
+        });
+        selectors.warn_unavailable();
 
         Ok(Self {
             driver,
+            cipher,
             _gecko,
-            latency: config.latency,
+            latency: AtomicUsize::new(config.latency),
+            selectors: Mutex::new(Arc::new(selectors)),
         })
     }
 
+    /// Closes the browser, letting it write out its profile
+    pub async fn quit(self) -> WebDriverResult<()> {
+        self.driver.quit().await
+    }
+
+    /// The selectors currently in use
+    pub fn selectors(&self) -> Arc<Selectors> {
+        self.selectors.lock().unwrap().clone()
+    }
+
+    /// Changes how long to wait for the page after clicking around
+    pub fn set_latency(&self, latency: usize) {
+        self.latency.store(latency, Ordering::Relaxed);
+    }
+
+    /// Swaps in new selectors, used from the next lookup on
+    pub fn set_selectors(&self, selectors: Selectors) {
+        selectors.warn_unavailable();
+        *self.selectors.lock().unwrap() = Arc::new(selectors);
+    }
+
     /// Logs into Messenger. This will only work if we're not already logged in
     pub async fn login(&self, username: &str, password: &str) -> WebDriverResult<()> {
         self.driver.goto("https://messenger.com").await?;
+        let selectors = self.selectors();
         let email_input = self
             .driver
-            .query(By::Id("email"))
+            .locate(&selectors.login_email)
             .wait(
                 std::time::Duration::from_secs(10),
                 std::time::Duration::from_millis(100),
@@ -74,7 +181,7 @@ impl Browser {
 
         let password_input = self
             .driver
-            .query(By::Id("pass"))
+            .locate(&selectors.login_password)
             .wait(
                 std::time::Duration::from_secs(10),
                 std::time::Duration::from_millis(100),
@@ -85,7 +192,7 @@ impl Browser {
 
         let login_button = self
             .driver
-            .query(By::Id("loginbutton"))
+            .locate(&selectors.login_button)
             .wait(
                 std::time::Duration::from_secs(10),
                 std::time::Duration::from_millis(100),
@@ -100,24 +207,138 @@ impl Browser {
         Ok(())
     }
 
+    /// Works out where a login has got to, without waiting
+    pub async fn login_state(&self) -> WebDriverResult<LoginState> {
+        let selectors = self.selectors();
+        if self
+            .driver
+            .locate(&selectors.logged_in)
+            .nowait()
+            .exists()
+            .await?
+        {
+            return Ok(LoginState::LoggedIn);
+        }
+        if self
+            .driver
+            .locate(&selectors.captcha)
+            .nowait()
+            .exists()
+            .await?
+        {
+            return Ok(LoginState::Challenge(Challenge::Captcha));
+        }
+        if self
+            .driver
+            .locate(&selectors.two_factor_input)
+            .nowait()
+            .exists()
+            .await?
+        {
+            return Ok(LoginState::Challenge(Challenge::TwoFactor));
+        }
+        if self
+            .driver
+            .current_url()
+            .await?
+            .path()
+            .contains("checkpoint")
+            || self
+                .driver
+                .locate(&selectors.checkpoint)
+                .nowait()
+                .exists()
+                .await?
+        {
+            return Ok(LoginState::Challenge(Challenge::Checkpoint));
+        }
+        Ok(LoginState::Pending)
+    }
+
+    /// Types a two-factor or checkpoint code and submits it
+    pub async fn submit_login_code(&self, code: &str) -> WebDriverResult<()> {
+        let selectors = self.selectors();
+        let input = self
+            .driver
+            .locate(&selectors.two_factor_input)
+            .wait(
+                std::time::Duration::from_secs(5),
+                std::time::Duration::from_millis(100),
+            )
+            .first()
+            .await?;
+        input.clear().await?;
+        input.send_keys(code).await?;
+        match self
+            .driver
+            .locate(&selectors.login_code_submit)
+            .nowait()
+            .first_opt()
+            .await?
+        {
+            Some(button) => button.click().await?,
+            None => input.send_keys(Key::Enter + "").await?,
+        }
+        Ok(())
+    }
+
+    /// Clicks through a checkpoint page that only wants a "Continue",
+    /// such as being asked to remember the browser. Returns whether there was one.
+    pub async fn continue_checkpoint(&self) -> WebDriverResult<bool> {
+        let selectors = self.selectors();
+        if self
+            .driver
+            .locate(&selectors.two_factor_input)
+            .nowait()
+            .exists()
+            .await?
+        {
+            return Ok(false);
+        }
+        match self
+            .driver
+            .locate(&selectors.login_code_submit)
+            .nowait()
+            .first_opt()
+            .await?
+        {
+            Some(button) => {
+                button.click().await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Takes a screenshot as a base64 PNG
+    pub async fn screenshot_base64(&self) -> WebDriverResult<String> {
+        let png = self.driver.screenshot_as_png().await?;
+        Ok(base64::engine::general_purpose::STANDARD.encode(png))
+    }
+
+    /// The page's URL
+    pub async fn current_url(&self) -> WebDriverResult<String> {
+        Ok(self.driver.current_url().await?.to_string())
+    }
+
     /// Checks for the xs cookie (token) and the presence of the 'Chats' h1 element. It loads before XHR requests are made
     pub async fn is_logged_in(&self) -> bool {
         // Does the xs cookie exist?
         self.driver.get_named_cookie("xs").await.is_ok()
             && self
                 .driver
-                .find(By::XPath("//a[@aria-label=\"New message\"]"))
+                .locate(&self.selectors().logged_in)
+                .nowait()
+                .exists()
                 .await
-                .is_ok()
+                .unwrap_or(false)
     }
 
     /// Enters the dumb e2ee pin Facebook is shoving down everyones' throats
     pub async fn enter_e2ee_pin(&self, pin: String) {
         if let Ok(pin_input) = self
             .driver
-            .query(By::XPath(
-                "//input[@id='mw-numeric-code-input-prevent-composer-focus-steal']",
-            ))
+            .locate(&self.selectors().e2ee_pin_input)
             .wait(
                 std::time::Duration::from_secs(20),
                 std::time::Duration::from_millis(500),
@@ -138,26 +359,43 @@ impl Browser {
 
     /// Gets all the chats on the side bar. Includes whether or not they are unread.
     pub async fn get_chats(&self) -> WebDriverResult<Vec<crate::chat::ChatOption>> {
-        crate::chat::ChatOption::get_all(&self.driver).await
+        crate::chat::ChatOption::get_all(&self.driver, &self.selectors()).await
     }
 
     /// Navigates the browser to the chat with the given id.
     /// Attempts to find it on the side bar to click that object.
     /// If it's not found, it will just navigate via URL.
-    pub async fn go_to_chat(&self, id: &str) -> WebDriverResult<()> {
+    pub async fn go_to_chat(&self, id: &str) -> Result<(), SendError> {
         self.decline_call().await.unwrap();
         let chats = self.get_chats().await?;
         match chats.iter().find(|c| c.id == id) {
             Some(chat) => {
-                chat.click(self.latency).await?;
+                chat.click(self.latency.load(Ordering::Relaxed)).await?;
             }
             None => {
                 // Manually go
                 self.driver
                     .goto(format!("https://www.messenger.com/t/{}", id))
                     .await?;
+                // Messenger keeps the URL but shows no conversation for chats that don't exist
+                if self
+                    .driver
+                    .locate(&self.selectors().conversation_grid)
+                    .wait(
+                        std::time::Duration::from_secs(10),
+                        std::time::Duration::from_millis(100),
+                    )
+                    .first()
+                    .await
+                    .is_err()
+                {
+                    return Err(SendError::ChatNotFound);
+                }
             }
         }
+        if self.get_current_chat().await? != id {
+            return Err(SendError::ChatNotFound);
+        }
         Ok(())
     }
 
@@ -167,16 +405,23 @@ impl Browser {
         // aria-lable = "Decline"
         let decline = self
             .driver
-            .find(By::XPath("//div[@aria-label=\"Decline\"]"))
-            .await;
+            .locate(&self.selectors().decline_call)
+            .nowait()
+            .first_opt()
+            .await?;
 
-        if let Ok(d) = decline {
+        if let Some(d) = decline {
             info!("Declining call");
             d.click().await?;
         }
         Ok(())
     }
 
+    /// Navigates the tab to a URL
+    pub async fn goto(&self, url: &str) -> WebDriverResult<()> {
+        self.driver.goto(url).await
+    }
+
     /// Refreshes the tab
     pub async fn refresh(&self) -> WebDriverResult<()> {
         self.driver.refresh().await?;
@@ -250,79 +495,188 @@ impl Browser {
         }
     }
 
+    /// Checks the critical selectors against the current page
+    pub async fn check_selectors(&self) -> WebDriverResult<Vec<SelectorCheck>> {
+        crate::selectors::check(&self.driver, &self.selectors()).await
+    }
+
     /// Gets the ID of the current chat
     pub async fn get_current_chat(&self) -> WebDriverResult<String> {
         let current_url = self.driver.current_url().await?;
         let id = current_url
             .path()
             .split('/')
-            .filter(|x| !x.is_empty())
-            .last()
+            .rfind(|x| !x.is_empty())
             .unwrap();
         Ok(id.to_string())
     }
 
     /// Gets the list of all the messages in the current chat
     pub async fn get_messages(&self, last: bool) -> WebDriverResult<Vec<crate::chat::ChatMessage>> {
-        crate::chat::ChatMessage::get(&self.driver, self.get_current_chat().await?, last).await
+        crate::chat::ChatMessage::get(
+            &self.driver,
+            self.get_current_chat().await?,
+            last,
+            &self.selectors(),
+        )
+        .await
     }
 
-    /// Sends a message to the current chat
-    pub async fn send_message(&self, message: &str) -> WebDriverResult<()> {
-        self.decline_call().await.unwrap();
+    /// Hovers a message in the current chat and clicks Reply on it,
+    /// so the next message sent quotes it
+    pub async fn start_reply(&self, target: &MessageRef) -> Result<(), SendError> {
+        self.decline_call().await?;
 
-        let chat_bar = match self
-            .driver
-            .query(By::XPath("//div[@role='textbox']"))
+        let selectors = self.selectors();
+        let reply_button =
+            require(&selectors.reply_button, "reply_button").map_err(SendError::Unsupported)?;
+        let chat_id = self.get_current_chat().await?;
+        let (row, _) = ChatMessage::find(&self.driver, chat_id, target, &selectors)
+            .await?
+            .ok_or_else(|| SendError::MessageNotFound(target.to_string()))?;
+        row.scroll_into_view().await?;
+        self.driver
+            .action_chain()
+            .move_to_element_center(&row)
+            .perform()
+            .await?;
+
+        let reply = row
+            .locate(reply_button)
             .wait(
-                std::time::Duration::from_secs(5),
+                std::time::Duration::from_secs(2),
                 std::time::Duration::from_millis(100),
             )
             .first()
-            .await
-        {
-            Ok(c) => c,
-            Err(_) => {
-                warn!("Unable to get sender box by textbox role");
+            .await?;
+        reply.click().await?;
+        tokio::time::sleep(std::time::Duration::from_millis(
+            self.latency.load(Ordering::Relaxed) as u64,
+        ))
+        .await;
+        Ok(())
+    }
+
+    /// Adds or removes our reaction on a message in the current chat.
+    /// Messenger toggles a reaction when it's picked again, so nothing is clicked
+    /// if the message already has (or doesn't have) our reaction.
+    pub async fn react(
+        &self,
+        target: &MessageRef,
+        emoji: &str,
+        remove: bool,
+    ) -> Result<(), SendError> {
+        self.decline_call().await?;
+
+        let selectors = self.selectors();
+        for (locator, name) in [
+            (&selectors.react_button, "react_button"),
+            (&selectors.reaction_option, "reaction_option"),
+        ] {
+            require(locator, name).map_err(SendError::Unsupported)?;
+        }
+        let chat_id = self.get_current_chat().await?;
+        let (row, message) = ChatMessage::find(&self.driver, chat_id, target, &selectors)
+            .await?
+            .ok_or_else(|| SendError::MessageNotFound(target.to_string()))?;
+        let emoji = chat::normalize_emoji(emoji);
+        let ours = message
+            .reactions
+            .iter()
+            .any(|r| r.emoji == emoji && r.sender.as_deref() == Some(chat::OWN_SENDER));
+        if ours != remove {
+            debug!("Reaction {emoji} is already as asked on {target}");
+            return Ok(());
+        }
+
+        row.scroll_into_view().await?;
+        self.driver
+            .action_chain()
+            .move_to_element_center(&row)
+            .perform()
+            .await?;
+        row.locate(&selectors.react_button)
+            .wait(
+                std::time::Duration::from_secs(2),
+                std::time::Duration::from_millis(100),
+            )
+            .first()
+            .await?
+            .click()
+            .await?;
+
+        let option: Vec<String> = selectors
+            .reaction_option
+            .iter()
+            .map(|l| l.replace("{emoji}", &emoji))
+            .collect();
+        let quick = self
+            .driver
+            .locate(&option)
+            .wait(
+                std::time::Duration::from_secs(2),
+                std::time::Duration::from_millis(100),
+            )
+            .first_opt()
+            .await?;
+        let button = match quick {
+            Some(b) => b,
+            None if selectors.reaction_more.is_empty() => {
+                return Err(SendError::Rejected(format!(
+                    "{emoji} isn't a quick reaction, and `reaction_more` is turned off"
+                )));
+            }
+            None => {
+                // Not one of the quick reactions, so look in the full picker
+                self.driver
+                    .locate(&selectors.reaction_more)
+                    .nowait()
+                    .first()
+                    .await?
+                    .click()
+                    .await?;
                 self.driver
-                    .find(By::XPath("//div[@aria-label='Message']"))
+                    .locate(&option)
+                    .wait(
+                        std::time::Duration::from_secs(2),
+                        std::time::Duration::from_millis(100),
+                    )
+                    .first_opt()
                     .await?
+                    .ok_or_else(|| {
+                        SendError::Rejected(format!("{emoji} isn't in the reaction picker"))
+                    })?
             }
         };
+        button.click().await?;
+        Ok(())
+    }
+
+    /// Types and sends a message to the current chat
+    pub async fn send_message(&self, message: &str, style: &Style) -> Result<(), SendError> {
+        self.decline_call().await.unwrap();
+
+        let selectors = self.selectors();
+        let chat_bar = self
+            .driver
+            .locate(&selectors.textbox)
+            .wait(
+                std::time::Duration::from_secs(5),
+                std::time::Duration::from_millis(100),
+            )
+            .first()
+            .await?;
         chat_bar.click().await?;
 
-        let mut rand_gen = rand::thread_rng();
-        for c in message.chars() {
-            self.decline_call().await.unwrap();
-            let x = rand_gen.gen_range(1..=30);
-            if x == 7 {
-                for asdf in "asdf".chars() {
-                    chat_bar.send_keys(String::from(asdf)).await?;
-                    tokio::time::sleep(std::time::Duration::from_millis(
-                        rand_gen.gen_range(10..=20),
-                    ))
-                    .await;
-                }
-                for _ in 0..4 {
-                    chat_bar.send_keys(Key::Backspace + "").await?;
-                    tokio::time::sleep(std::time::Duration::from_millis(
-                        rand_gen.gen_range(10..=20),
-                    ))
-                    .await;
-                }
-            }
-            chat_bar.send_keys(String::from(c)).await?;
-            tokio::time::sleep(std::time::Duration::from_millis(
-                rand_gen.gen_range(10..=20),
-            ))
-            .await;
-        }
+        typing::type_text(&self.driver, &chat_bar, message, style).await?;
         chat_bar.send_keys(Key::Enter + "").await?;
 
-        if let Ok(send_button) = self
+        if let Some(send_button) = self
             .driver
-            .find(By::XPath("//div[@aria-label='Press enter to send']"))
-            .await
+            .locate(&selectors.send_button)
+            .nowait()
+            .first_opt()
+            .await?
         {
             let _ = send_button.click().await;
         }
@@ -330,27 +684,20 @@ impl Browser {
         Ok(())
     }
 
-    pub async fn send_file(&self, path: &str) -> WebDriverResult<()> {
+    /// Sends a file to the current chat by dropping it on the chat bar
+    pub async fn send_file(&self, path: &str) -> Result<(), SendError> {
         self.decline_call().await.unwrap();
 
-        let chat_bar = match self
+        let selectors = self.selectors();
+        let chat_bar = self
             .driver
-            .query(By::XPath("//div[@role='textbox']"))
+            .locate(&selectors.textbox)
             .wait(
                 std::time::Duration::from_secs(5),
                 std::time::Duration::from_millis(100),
             )
             .first()
-            .await
-        {
-            Ok(c) => c,
-            Err(_) => {
-                warn!("Unable to get sender box by textbox role");
-                self.driver
-                    .find(By::XPath("//div[@aria-label='Message']"))
-                    .await?
-            }
-        };
+            .await?;
         chat_bar.click().await?;
 
         let ret = self
@@ -369,42 +716,54 @@ impl Browser {
         ret.send_keys(path).await?;
 
         // Detect an invalid file format
-        if let Ok(dialogue) = self
+        if let Some(dialogue) = self
             .driver
-            .find(By::XPath("//div[@aria-label='Invalid file format']"))
-            .await
+            .locate(&selectors.invalid_file_dialog)
+            .nowait()
+            .first_opt()
+            .await?
         {
             warn!("File upload failed: invalid file format!");
             // Close the box
             dialogue
-                .find(By::XPath("//div[@aria-label='Close']"))
+                .locate(&selectors.dialog_close)
+                .nowait()
+                .first()
                 .await?
                 .click()
                 .await?;
+            return Err(SendError::InvalidFileFormat);
         }
 
         // Detect a file upload
-        if let Ok(dialogue) = self
+        if let Some(dialogue) = self
             .driver
-            .find(By::XPath("//div[@aria-label='Failed to upload files']"))
-            .await
+            .locate(&selectors.upload_failed_dialog)
+            .nowait()
+            .first_opt()
+            .await?
         {
             warn!("File upload failed! (Is the file below 25 MB?)");
             // Close the box
             dialogue
-                .find(By::XPath("//div[@aria-label='Close']"))
+                .locate(&selectors.dialog_close)
+                .nowait()
+                .first()
                 .await?
                 .click()
                 .await?;
+            return Err(SendError::UploadFailed);
         }
 
         chat_bar.click().await?;
         chat_bar.send_keys(Key::Enter + "").await?;
 
-        if let Ok(send_button) = self
+        if let Some(send_button) = self
             .driver
-            .find(By::XPath("//div[@aria-label='Press enter to send']"))
-            .await
+            .locate(&selectors.send_button)
+            .nowait()
+            .first_opt()
+            .await?
         {
             let _ = send_button.click().await;
         }
@@ -415,46 +774,64 @@ impl Browser {
     /// Dumps the cookies to cookies.json so we don't have to login every time
     pub async fn dump_cookies(&self) -> WebDriverResult<()> {
         let cookies = self.driver.get_all_cookies().await?;
-        let mut file = match std::fs::File::create("cookies.json") {
-            Ok(file) => file,
-            Err(e) => {
-                error!("Failed to create cookies.json: {}", e);
-                return Err(WebDriverError::CustomError(format!(
-                    "Failed to create cookies.json: {}",
-                    e
-                )));
-            }
-        };
         let json_cookies: Vec<JsonCookie> = cookies
             .iter()
             .map(|c| JsonCookie {
                 name: c.name().to_owned(),
                 value: c.value().to_owned(),
+                domain: c.domain().map(str::to_owned),
+                path: c.path().map(str::to_owned),
+                expiry: c.expires_datetime().map(|e| e.unix_timestamp()),
+                secure: c.secure(),
+                http_only: c.http_only(),
+                same_site: c.same_site().map(|s| s.to_string()),
             })
             .collect();
-        serde_json::to_writer_pretty(&mut file, &json_cookies).unwrap();
-        Ok(())
+        self.save_session_file(
+            "cookies.json",
+            &serde_json::to_vec_pretty(&json_cookies).unwrap(),
+        )
     }
 
     /// Loads the cookies from cookies.json so we don't have to login every time
     pub async fn load_cookies(&self) -> WebDriverResult<()> {
-        let mut file = match std::fs::File::open("cookies.json") {
-            Ok(file) => file,
-            Err(_) => {
-                warn!("No cookies.json file found");
-                return Ok(());
-            }
+        let Some(contents) = self.load_session_file("cookies.json") else {
+            return Ok(());
         };
-        let mut json_cookies: Vec<JsonCookie> = serde_json::from_reader(&mut file).unwrap();
+        let mut json_cookies: Vec<JsonCookie> = serde_json::from_slice(&contents)
+            .map_err(|e| WebDriverError::CustomError(format!("Invalid saved cookies: {e}")))?;
+        let now = OffsetDateTime::now_utc().unix_timestamp();
         for cookie in json_cookies.drain(..) {
-            self.driver
-                .add_cookie(
-                    Cookie::build(cookie.name, cookie.value)
-                        .path("/")
-                        .domain("messenger.com")
-                        .finish(),
-                )
-                .await?;
+            if cookie.expiry.is_some_and(|e| e < now) {
+                debug!("Skipping expired cookie {}", cookie.name);
+                continue;
+            }
+            let name = cookie.name.clone();
+            let mut builder = Cookie::build(cookie.name, cookie.value)
+                .path(cookie.path.unwrap_or("/".to_string()))
+                .domain(cookie.domain.unwrap_or("messenger.com".to_string()));
+            if let Some(expiry) = cookie
+                .expiry
+                .and_then(|e| OffsetDateTime::from_unix_timestamp(e).ok())
+            {
+                builder = builder.expires(expiry);
+            }
+            if let Some(secure) = cookie.secure {
+                builder = builder.secure(secure);
+            }
+            if let Some(http_only) = cookie.http_only {
+                builder = builder.http_only(http_only);
+            }
+            match cookie.same_site.as_deref() {
+                Some("Strict") => builder = builder.same_site(SameSite::Strict),
+                Some("Lax") => builder = builder.same_site(SameSite::Lax),
+                Some("None") => builder = builder.same_site(SameSite::None),
+                _ => {}
+            }
+            // Cookies for other domains can't be set from this page
+            if let Err(e) = self.driver.add_cookie(builder.finish()).await {
+                warn!("Unable to load cookie {name}: {e}");
+            }
         }
         self.driver.refresh().await?;
         tokio::time::sleep(std::time::Duration::from_secs(3)).await;
@@ -462,21 +839,256 @@ impl Browser {
         Ok(())
     }
 
+    /// Downloads an attachment with the page's session, returning its bytes and MIME type
+    pub async fn fetch_media(
+        &self,
+        url: &str,
+        max_size: u64,
+    ) -> WebDriverResult<(Vec<u8>, String)> {
+        let ret = self
+            .driver
+            .execute_async(
+                include_str!("fetch_media.js"),
+                vec![Value::from(url), Value::from(max_size)],
+            )
+            .await?;
+        let res = ret.json();
+        if let Some(e) = res.get("error") {
+            return Err(WebDriverError::CustomError(format!(
+                "Unable to download {url}: {e}"
+            )));
+        }
+        let data = res.get("data").and_then(Value::as_str).unwrap_or_default();
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .map_err(|e| WebDriverError::CustomError(format!("Invalid download of {url}: {e}")))?;
+        let mime = res.get("mime").and_then(Value::as_str).unwrap_or_default();
+        Ok((bytes, mime.to_string()))
+    }
+
+    /// Saves localStorage and IndexedDB, where Messenger keeps the E2EE keys, to storage.json
+    pub async fn dump_storage(&self) -> WebDriverResult<()> {
+        let ret = self
+            .driver
+            .execute_async(include_str!("storage_dump.js"), Vec::new())
+            .await?;
+        let dump = ret.json();
+        if let Some(e) = dump.get("error") {
+            return Err(WebDriverError::CustomError(format!(
+                "Unable to read the page storage: {e}"
+            )));
+        }
+        if let Some(skipped) = dump
+            .get("skipped")
+            .and_then(Value::as_u64)
+            .filter(|s| *s > 0)
+        {
+            warn!("Skipped {skipped} storage records that can't be saved");
+        }
+        self.save_session_file("storage.json", &serde_json::to_vec(dump).unwrap())
+    }
+
+    /// Loads storage.json back into the page.
+    /// This has to happen before Messenger's scripts open their databases,
+    /// so it's done from a page on the same origin that has none.
+    pub async fn load_storage(&self) -> WebDriverResult<()> {
+        let Some(contents) = self.load_session_file("storage.json") else {
+            return Ok(());
+        };
+        let dump: Value = serde_json::from_slice(&contents)
+            .map_err(|e| WebDriverError::CustomError(format!("Invalid saved page storage: {e}")))?;
+        let current = self.driver.current_url().await?;
+        self.driver
+            .goto("https://www.messenger.com/robots.txt")
+            .await?;
+        let ret = self
+            .driver
+            .execute_async(include_str!("storage_load.js"), vec![dump])
+            .await?;
+        if let Some(e) = ret.json().get("error") {
+            warn!("Unable to load the page storage: {e}");
+        } else if let Some(failed) = ret
+            .json()
+            .get("failed")
+            .and_then(Value::as_u64)
+            .filter(|f| *f > 0)
+        {
+            warn!("{failed} storage records couldn't be loaded");
+        }
+        self.driver.goto(current.as_str()).await?;
+        Ok(())
+    }
+
     /// Wipes the cookies
     pub async fn delete_cookies(&self) -> WebDriverResult<()> {
         self.driver.delete_all_cookies().await?;
         Ok(())
     }
+
+    /// Writes a session file, encrypted if a passphrase or key file is set
+    fn save_session_file(&self, path: &str, contents: &[u8]) -> WebDriverResult<()> {
+        secrets::save(path, contents, self.cipher.as_ref()).map_err(|e| {
+            error!("Failed to write {path}: {e}");
+            WebDriverError::CustomError(format!("Failed to write {path}: {e}"))
+        })
+    }
+
+    /// Reads a session file. A missing or unreadable one means starting fresh.
+    fn load_session_file(&self, path: &str) -> Option<Vec<u8>> {
+        match secrets::load(path, self.cipher.as_ref()) {
+            Ok(Some(contents)) => Some(contents),
+            Ok(None) => {
+                warn!("No {path} file found");
+                None
+            }
+            Err(e) => {
+                error!("{e}");
+                None
+            }
+        }
+    }
+}
+
+impl SendError {
+    /// A stable name for the error that children can match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            SendError::ChatNotFound => "chat_not_found",
+            SendError::InvalidFileFormat => "invalid_file_format",
+            SendError::UploadFailed => "upload_failed",
+            SendError::MessageNotFound(_) => "message_not_found",
+            SendError::InvalidConfig(_) => "invalid_config",
+            SendError::Rejected(_) => "rejected",
+            SendError::Unsupported(_) => "unsupported",
+            SendError::WebDriver(_) => "webdriver",
+        }
+    }
+}
+
+impl From<WebDriverError> for SendError {
+    fn from(e: WebDriverError) -> Self {
+        SendError::WebDriver(e)
+    }
+}
+
+impl Display for SendError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::ChatNotFound => write!(f, "chat not found"),
+            SendError::InvalidFileFormat => write!(f, "invalid file format"),
+            SendError::UploadFailed => write!(f, "file upload failed, is the file below 25 MB?"),
+            SendError::MessageNotFound(m) => write!(f, "{m} isn't in the loaded history"),
+            SendError::InvalidConfig(e) => write!(f, "{e}"),
+            SendError::Rejected(e) => write!(f, "{e}"),
+            SendError::Unsupported(e) => write!(f, "{e}"),
+            SendError::WebDriver(e) => write!(f, "WebDriver error: {e}"),
+        }
+    }
+}
+
+fn firefox_caps(config: &Config) -> WebDriverResult<FirefoxCapabilities> {
+    let mut caps = DesiredCapabilities::firefox();
+    caps.add_firefox_arg("--width=1920")?;
+    caps.add_firefox_arg("--height=1080")?;
+    if let Some(user_agent) = &config.gecko.user_agent {
+        let mut prefs = FirefoxPreferences::new();
+        prefs.set_user_agent(user_agent.clone())?;
+        caps.set_preferences(prefs)?;
+    }
+    if let Some(binary) = &config.gecko.binary {
+        caps.set_firefox_binary(std::path::Path::new(binary))?;
+    }
+    if let Some(profile) = profile_dir(config)? {
+        caps.add_firefox_arg("-profile")?;
+        caps.add_firefox_arg(&profile)?;
+    }
+
+    if config.gecko.headless {
+        caps.set_headless()?;
+    }
+    Ok(caps)
+}
+
+fn chromium_caps(config: &Config) -> WebDriverResult<ChromeCapabilities> {
+    let mut caps = DesiredCapabilities::chrome();
+    caps.add_chrome_arg("--disable-infobars")?;
+    caps.set_no_sandbox()?;
+    caps.add_chrome_arg("--disable-application-cache")?;
+    caps.set_disable_dev_shm_usage()?;
+    caps.add_chrome_arg("--start-maximized")?;
+    caps.add_chrome_arg("--disable-extensions")?;
+    caps.add_chrome_arg("--window-size=1920,1080")?;
+    let user_agent = config.gecko.user_agent.as_deref().unwrap_or(USER_AGENT);
+    caps.add_chrome_arg(&format!("--user-agent={user_agent}"))?;
+    if let Some(binary) = &config.gecko.binary {
+        caps.set_binary(binary)?;
+    }
+    if let Some(profile) = profile_dir(config)? {
+        caps.add_chrome_arg(&format!("--user-data-dir={profile}"))?;
+    }
+
+    if config.gecko.headless {
+        caps.set_headless()?;
+        caps.set_disable_gpu()?;
+    }
+    Ok(caps)
+}
+
+/// The profile folder to start the browser with, created if Holly runs the driver
+fn profile_dir(config: &Config) -> WebDriverResult<Option<String>> {
+    let Some(profile) = &config.gecko.profile else {
+        return Ok(None);
+    };
+    if !config.gecko.spawn {
+        // The folder is on the WebDriver's machine
+        return Ok(Some(profile.clone()));
+    }
+    let path = std::fs::create_dir_all(profile)
+        .and_then(|_| std::fs::canonicalize(profile))
+        .map_err(|e| {
+            WebDriverError::CustomError(format!("Unable to create profile folder {profile}: {e}"))
+        })?;
+    Ok(Some(path.to_string_lossy().to_string()))
+}
+
+/// Connects to the WebDriver, retrying while it starts up
+async fn connect(
+    url: &str,
+    caps: impl Into<Capabilities> + Clone,
+    config: &Config,
+) -> WebDriverResult<WebDriver> {
+    let mut attempt = 1;
+    loop {
+        match WebDriver::new(url, caps.clone()).await {
+            Ok(driver) => {
+                info!("Connected to WebDriver at {url}");
+                return Ok(driver);
+            }
+            Err(e) if attempt < config.gecko.connect_attempts => {
+                debug!("WebDriver at {url} isn't ready yet (attempt {attempt}): {e}");
+                attempt += 1;
+                tokio::time::sleep(std::time::Duration::from_millis(config.gecko.connect_delay))
+                    .await;
+            }
+            Err(e) => {
+                error!("Unable to connect to WebDriver at {url}: {e}");
+                return Err(e);
+            }
+        }
+    }
 }
 
-fn launch_driver(path: &str, port: u16) -> Child {
-    Command::new(path)
-        .arg("-p")
-        .arg(port.to_string())
+fn launch_driver(browser: BrowserKind, path: &str, port: u16) -> Child {
+    let mut command = Command::new(path);
+    match browser {
+        BrowserKind::Firefox => command.arg("-p").arg(port.to_string()),
+        BrowserKind::Chromium => command.arg(format!("--port={port}")),
+    };
+    command
         .kill_on_drop(true)
         .stdout(Stdio::null())
         .stderr(Stdio::null())
         .stdin(Stdio::null())
         .spawn()
-        .expect("Unable to spawn geckodriver! Check that the path is correct!")
+        .expect("Unable to spawn the WebDriver! Check that the path is correct!")
 }
@@ -4,28 +4,100 @@
 // As of writing, the way to compare messages was to compare the content and sender.
 // This meant that if a person sent the same message twice, it was ignored.
 // Facebook ships roughly 13 messages on load, which means we can compare a tree.
+// When the page gives messages ids, those are compared instead.
+// Reactions are tracked separately, per message, so changes to them can be
+// reported without the message looking new. An edited or unsent message still
+// lines up with the one it replaced, and is reported as a revision instead.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use log::{debug, info, warn};
 
-use crate::chat::ChatMessage;
+use crate::chat::{ChatMessage, MessageRef, Reaction, ReactionChange};
 
 pub struct Cache {
     inner: HashMap<String, Vec<ChatMessage>>,
+    /// Edits and unsends found by `check` that haven't been taken yet
+    revisions: Vec<Revision>,
+    /// Reactions on the messages last seen in each chat
+    reactions: HashMap<String, HashMap<String, Vec<Reaction>>>,
 }
 
 impl Cache {
     pub fn new() -> Self {
         Self {
             inner: HashMap::new(),
+            revisions: Vec::new(),
+            reactions: HashMap::new(),
         }
     }
 
+    /// Reactions added to and removed from messages that were already on screen.
+    /// The first look at a chat only records them.
+    pub fn reactions(
+        &mut self,
+        chat_id: &str,
+        messages: &[ChatMessage],
+    ) -> (Vec<ReactionChange>, Vec<ReactionChange>) {
+        let current = messages
+            .iter()
+            .map(|m| (reaction_key(m), m.reactions.clone()))
+            .collect();
+        let Some(known) = self.reactions.insert(chat_id.to_owned(), current) else {
+            return (Vec::new(), Vec::new());
+        };
+
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+        for message in messages {
+            let Some(old) = known.get(&reaction_key(message)) else {
+                continue;
+            };
+            let change = |r: &Reaction| ReactionChange {
+                chat_id: chat_id.to_owned(),
+                message: MessageRef::to(message),
+                emoji: r.emoji.clone(),
+                sender: r.sender.clone(),
+            };
+            added.extend(
+                message
+                    .reactions
+                    .iter()
+                    .filter(|r| !old.contains(r))
+                    .map(change),
+            );
+            removed.extend(
+                old.iter()
+                    .filter(|r| !message.reactions.contains(r))
+                    .map(change),
+            );
+        }
+        (added, removed)
+    }
+
     pub async fn check(
         &mut self,
         chat_id: &str,
         new_messages: &Vec<ChatMessage>,
+    ) -> Option<Vec<ChatMessage>> {
+        // A message that was unsent before we saw it isn't worth passing on
+        let new: Vec<ChatMessage> = self
+            .find_new(chat_id, new_messages)?
+            .into_iter()
+            .filter(|m| !m.unsent)
+            .collect();
+        (!new.is_empty()).then_some(new)
+    }
+
+    /// Messages that were edited or unsent since the last `check`
+    pub fn take_revisions(&mut self) -> Vec<Revision> {
+        std::mem::take(&mut self.revisions)
+    }
+
+    fn find_new(
+        &mut self,
+        chat_id: &str,
+        new_messages: &Vec<ChatMessage>,
     ) -> Option<Vec<ChatMessage>> {
         let old_messages = match self.inner.get(chat_id) {
             Some(o) => o,
@@ -56,18 +128,30 @@ impl Cache {
             return None;
         }
 
+        let with_ids = |m: &Vec<ChatMessage>| m.iter().all(|m| m.id.is_some());
+        if with_ids(old_messages) && with_ids(new_messages) {
+            return self.check_ids(chat_id, new_messages);
+        }
+
         let mut new_count = 0;
         let mut old_count = 0;
+        let mut revisions = Vec::new();
         let last_old_message = old_messages.last().cloned();
         let last_new_message = new_messages.last().cloned();
         loop {
-            if old_messages[old_count] == new_messages[new_count] {
-                new_count += 1;
+            match compare(&old_messages[old_count], &new_messages[new_count]) {
+                Slot::Same => new_count += 1,
+                Slot::Revised(r) => {
+                    revisions.push(*r);
+                    new_count += 1;
+                }
+                Slot::Different => {}
             }
             old_count += 1;
 
             if old_count == old_messages.len() {
                 self.inner.insert(chat_id.to_owned(), new_messages.clone());
+                self.revisions.append(&mut revisions);
                 if new_count > 3 {
                     return Some(new_messages[new_count..].to_vec());
                 } else {
@@ -86,6 +170,34 @@ impl Cache {
         }
     }
 
+    /// Everything after the last message that was already in the cache
+    fn check_ids(
+        &mut self,
+        chat_id: &str,
+        new_messages: &[ChatMessage],
+    ) -> Option<Vec<ChatMessage>> {
+        let old_messages = self
+            .inner
+            .insert(chat_id.to_owned(), new_messages.to_vec())?;
+        let seen: HashSet<&Option<String>> = old_messages.iter().map(|m| &m.id).collect();
+        for old in &old_messages {
+            let Some(new) = new_messages.iter().find(|m| m.id == old.id) else {
+                continue;
+            };
+            if let Slot::Revised(r) = compare(old, new) {
+                self.revisions.push(*r);
+            }
+        }
+        match new_messages.iter().rposition(|m| seen.contains(&m.id)) {
+            Some(i) if i + 1 == new_messages.len() => None,
+            Some(i) => Some(new_messages[i + 1..].to_vec()),
+            None => {
+                warn!("New messages had no match on old messages");
+                Some(vec![new_messages.last()?.clone()])
+            }
+        }
+    }
+
     pub fn check_key(&self, key: &str) -> bool {
         self.inner.contains_key(key)
     }
@@ -94,3 +206,221 @@ impl Cache {
         self.inner.len()
     }
 }
+
+/// Tells messages apart for reactions, by id if there is one
+fn reaction_key(message: &ChatMessage) -> String {
+    message
+        .id
+        .clone()
+        .unwrap_or_else(|| format!("{}\n{}", message.sender, message.content))
+}
+
+/// A message that changed after it was first read
+#[derive(Debug, Clone)]
+pub enum Revision {
+    /// The message as it was, and what it says now
+    Edited {
+        before: ChatMessage,
+        content: String,
+    },
+    /// The message as it was before it was unsent
+    Unsent(ChatMessage),
+}
+
+/// How a message compares to the cached one in the same spot
+enum Slot {
+    Same,
+    Revised(Box<Revision>),
+    Different,
+}
+
+fn compare(old: &ChatMessage, new: &ChatMessage) -> Slot {
+    if old == new {
+        return Slot::Same;
+    }
+    if old.sender != new.sender {
+        return Slot::Different;
+    }
+    if new.unsent && !old.unsent {
+        return Slot::Revised(Box::new(Revision::Unsent(old.clone())));
+    }
+    if new.edited && !new.unsent && new.content != old.content {
+        return Slot::Revised(Box::new(Revision::Edited {
+            before: old.clone(),
+            content: new.content.clone(),
+        }));
+    }
+    Slot::Different
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::Reaction;
+
+    fn message(id: Option<usize>, sender: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            sender: sender.to_string(),
+            content: content.to_string(),
+            chat_id: "100".to_string(),
+            id: id.map(|i| format!("mid.{i}")),
+            timestamp: None,
+            from_bot: false,
+            reply_to: None,
+            attachments: Vec::new(),
+            reactions: Vec::new(),
+            edited: false,
+            unsent: false,
+        }
+    }
+
+    /// Eight messages from alternating senders, with or without ids
+    fn history(ids: bool) -> Vec<ChatMessage> {
+        (0..8)
+            .map(|i| {
+                let sender = if i % 2 == 0 { "Alice" } else { "Bob" };
+                message(ids.then_some(i), sender, &format!("message {i}"))
+            })
+            .collect()
+    }
+
+    fn edit(m: &mut ChatMessage, content: &str) {
+        m.content = content.to_string();
+        m.edited = true;
+    }
+
+    fn unsend(m: &mut ChatMessage) {
+        m.content = format!("{} unsent a message", m.sender);
+        m.unsent = true;
+    }
+
+    /// Primes a cache with the history, which reports nothing
+    async fn primed(messages: &Vec<ChatMessage>) -> Cache {
+        let mut cache = Cache::new();
+        assert!(cache.check("100", messages).await.is_none());
+        assert_eq!(cache.reactions("100", messages), (Vec::new(), Vec::new()));
+        cache
+    }
+
+    #[tokio::test]
+    async fn no_change_reports_nothing() {
+        for ids in [true, false] {
+            let messages = history(ids);
+            let mut cache = primed(&messages).await;
+            for _ in 0..2 {
+                assert!(cache.check("100", &messages).await.is_none());
+                assert!(cache.take_revisions().is_empty());
+                assert_eq!(cache.reactions("100", &messages), (Vec::new(), Vec::new()));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn new_messages() {
+        for ids in [true, false] {
+            let mut messages = history(ids);
+            let mut cache = primed(&messages).await;
+            // The oldest scrolls out of view as two arrive
+            messages.remove(0);
+            messages.push(message(ids.then_some(8), "Carol", "hi"));
+            messages.push(message(ids.then_some(9), "Carol", "hi"));
+            let new = cache.check("100", &messages).await.unwrap();
+            assert_eq!(new.len(), 2, "with ids: {ids}");
+            assert!(cache.take_revisions().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn edited_and_unsent() {
+        for ids in [true, false] {
+            let mut messages = history(ids);
+            let mut cache = primed(&messages).await;
+            edit(&mut messages[3], "message 3!");
+            unsend(&mut messages[5]);
+            assert!(
+                cache.check("100", &messages).await.is_none(),
+                "with ids: {ids}"
+            );
+
+            let revisions = cache.take_revisions();
+            assert_eq!(revisions.len(), 2, "with ids: {ids}");
+            match &revisions[0] {
+                Revision::Edited { before, content } => {
+                    assert_eq!(before.content, "message 3");
+                    assert_eq!(content, "message 3!");
+                }
+                r => panic!("expected an edit, got {r:?}"),
+            }
+            match &revisions[1] {
+                Revision::Unsent(before) => assert_eq!(before.content, "message 5"),
+                r => panic!("expected an unsend, got {r:?}"),
+            }
+
+            // Reported once, and not again on the next poll
+            assert!(cache.check("100", &messages).await.is_none());
+            assert!(cache.take_revisions().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn edited_alongside_new_messages() {
+        for ids in [true, false] {
+            let mut messages = history(ids);
+            let mut cache = primed(&messages).await;
+            edit(&mut messages[6], "message 6!");
+            messages.push(message(ids.then_some(8), "Carol", "hi"));
+            let new = cache.check("100", &messages).await.unwrap();
+            assert_eq!(new.len(), 1, "with ids: {ids}");
+            assert_eq!(new[0].content, "hi");
+            assert_eq!(cache.take_revisions().len(), 1, "with ids: {ids}");
+        }
+    }
+
+    #[tokio::test]
+    async fn unsent_before_being_seen() {
+        for ids in [true, false] {
+            let mut messages = history(ids);
+            let mut cache = primed(&messages).await;
+            let mut gone = message(ids.then_some(8), "Carol", "oops");
+            unsend(&mut gone);
+            messages.push(gone);
+            assert!(
+                cache.check("100", &messages).await.is_none(),
+                "with ids: {ids}"
+            );
+            assert!(cache.take_revisions().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn reactions_added_and_removed() {
+        for ids in [true, false] {
+            let mut messages = history(ids);
+            messages[1].reactions.push(Reaction {
+                emoji: "😂".to_string(),
+                sender: Some("Alice".to_string()),
+            });
+            let mut cache = primed(&messages).await;
+
+            messages[1].reactions.clear();
+            messages[2].reactions.push(Reaction {
+                emoji: "👍".to_string(),
+                sender: Some("Bob".to_string()),
+            });
+            // Reactions alone don't make a message new
+            assert!(cache.check("100", &messages).await.is_none());
+            assert!(cache.take_revisions().is_empty());
+
+            let (added, removed) = cache.reactions("100", &messages);
+            assert_eq!(added.len(), 1);
+            assert_eq!(added[0].emoji, "👍");
+            assert_eq!(added[0].sender.as_deref(), Some("Bob"));
+            assert_eq!(added[0].message.content.as_deref(), Some("message 2"));
+            assert_eq!(removed.len(), 1);
+            assert_eq!(removed[0].emoji, "😂");
+            assert_eq!(removed[0].message.content.as_deref(), Some("message 1"));
+
+            assert_eq!(cache.reactions("100", &messages), (Vec::new(), Vec::new()));
+        }
+    }
+}
@@ -1,14 +1,20 @@
 // Jackson Coxson
 
 use std::{
-    fmt::{Debug, Formatter},
+    fmt::{Debug, Display, Formatter},
     time::Duration,
 };
 
 use log::{debug, warn};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use thirtyfour::prelude::*;
 
+use crate::{
+    media::{Attachment, AttachmentKind},
+    selectors::{Locate, Selectors},
+};
+
 /// A chat found on the sidebar.
 /// Includes whether or not the chat is unread.
 pub struct ChatOption {
@@ -17,27 +23,173 @@ pub struct ChatOption {
     pub unread: bool,
 }
 
+/// A chat on the sidebar, without the element behind it.
+#[derive(Debug, Clone)]
+pub struct ChatPreview {
+    pub id: String,
+    pub unread: bool,
+}
+
+/// Who our own messages are from, since Messenger doesn't give them an avatar
+pub const OWN_SENDER: &str = "You";
+
+/// Attributes Messenger has kept message ids in, tried in order
+const ID_ATTRIBUTES: [&str; 3] = ["data-message-id", "data-mid", "data-id"];
+
+/// Attributes that can hold a message's time, tried before the element's text
+const TIME_ATTRIBUTES: [&str; 4] = [
+    "datetime",
+    "data-utime",
+    "data-tooltip-content",
+    "aria-label",
+];
+
 /// A message found in a chat.
-#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Reactions don't count when comparing messages, since they change after the message is sent,
+/// and neither does the timestamp, since times of day are dated by when they're read.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
     pub sender: String,
     pub content: String,
     pub chat_id: String,
+    /// Messenger's id for the message, if the page has one
+    #[serde(default)]
+    pub id: Option<String>,
+    /// When the message was sent in Unix milliseconds.
+    /// Holly fills in when it first read the message if the page doesn't say.
+    #[serde(default)]
+    pub timestamp: Option<i64>,
+    /// Whether Holly's account sent the message
+    #[serde(default)]
+    pub from_bot: bool,
+    /// The message this one replies to
+    #[serde(default)]
+    pub reply_to: Option<ReplyTarget>,
+    /// Photos, videos, voice clips, stickers and files on the message
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+    /// Whether the message was edited after it was sent
+    #[serde(default)]
+    pub edited: bool,
+    /// Whether this is the placeholder left by an unsent message
+    #[serde(default)]
+    pub unsent: bool,
+}
+
+/// An emoji someone reacted to a message with
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Reaction {
+    pub emoji: String,
+    /// Who reacted, or `None` if the page doesn't say
+    pub sender: Option<String>,
+}
+
+/// A reaction that appeared on or went away from a message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReactionChange {
+    pub chat_id: String,
+    pub message: MessageRef,
+    pub emoji: String,
+    /// Who reacted, or `None` if the page doesn't say
+    pub sender: Option<String>,
+}
+
+/// A message was edited
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageEdited {
+    pub chat_id: String,
+    /// The message as it was first read
+    pub message: MessageRef,
+    /// What it says now
+    pub content: String,
+}
+
+/// A message was unsent
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageUnsent {
+    pub chat_id: String,
+    /// The message as it was before it was unsent
+    pub message: MessageRef,
+}
+
+/// Points at a message in a chat, either by its id or by its sender and content
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageRef {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+impl MessageRef {
+    /// Whether this is the referenced message. Sender and content are only compared without an id.
+    pub fn matches(&self, message: &ChatMessage) -> bool {
+        if let Some(id) = &self.id {
+            return message.id.as_ref() == Some(id);
+        }
+        self.content.as_ref().is_some_and(|c| *c == message.content)
+            && self.sender.as_ref().is_none_or(|s| *s == message.sender)
+    }
+
+    /// Points at the message, by id if it has one
+    pub fn to(message: &ChatMessage) -> Self {
+        Self {
+            id: message.id.clone(),
+            sender: Some(message.sender.clone()),
+            content: Some(message.content.clone()),
+        }
+    }
+
+    /// Checks that there's something to match on
+    pub fn validate(&self) -> Result<(), String> {
+        if self.id.is_none() && self.content.is_none() {
+            return Err("a message reference needs an `id` or a `content`".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Display for MessageRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (&self.id, &self.sender, &self.content) {
+            (Some(id), _, _) => write!(f, "message {id}"),
+            (None, Some(sender), Some(content)) => write!(f, "{sender}'s message {content:?}"),
+            (None, _, content) => write!(f, "message {:?}", content.as_deref().unwrap_or_default()),
+        }
+    }
+}
+
+/// The message a reply quotes.
+/// `id` and `sender` are only known if the quoted message is still on screen.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplyTarget {
+    pub id: Option<String>,
+    pub sender: Option<String>,
+    pub content: String,
 }
 
 impl ChatOption {
     /// Gets all the chats in the sidebar
-    pub async fn get_all(driver: &WebDriver) -> WebDriverResult<Vec<ChatOption>> {
+    pub async fn get_all(
+        driver: &WebDriver,
+        selectors: &Selectors,
+    ) -> WebDriverResult<Vec<ChatOption>> {
         // Get the chats object
         let chats_object = driver
-            .query(By::XPath("//div[@aria-label=\"Chats\" and @role=\"grid\"]"))
+            .locate(&selectors.chats_grid)
             .wait(Duration::from_secs(15), Duration::from_millis(100))
             .first()
             .await?;
 
         // Get all the chat options
         let chat_options = chats_object
-            .find_all(By::XPath(".//div[@class=\"x78zum5 xdt5ytf\"]"))
+            .locate(&selectors.chat_row)
+            .nowait()
+            .all()
             .await?;
 
         // Create a vector to store the chat options
@@ -45,18 +197,20 @@ impl ChatOption {
 
         for chat in chat_options {
             // Get chat ID
-            let link_object = chat.find(By::XPath(".//a[@role=\"link\"]")).await?;
+            let link_object = chat.locate(&selectors.chat_link).nowait().first().await?;
             let id = link_object
                 .attr("href")
                 .await?
                 .unwrap()
-                .replace(['/', 't'], "").replace("e2ee", "");
+                .replace(['/', 't'], "")
+                .replace("e2ee", "");
 
             // Determine if the unread marker is there
-            let unread_marker = chat
-                .find(By::XPath(".//span[@class=\"x6s0dn4 xzolkzo x12go9s9 x1rnf11y xprq8jg x9f619 x3nfvp2 xl56j7k xwnonoy x170jfvy x1fsd2vl\"]"))
-                .await;
-            let unread = unread_marker.is_ok();
+            let unread = chat
+                .locate(&selectors.unread_marker)
+                .nowait()
+                .exists()
+                .await?;
 
             // Add the chat option to the vector
             chat_options_vec.push(ChatOption {
@@ -83,12 +237,33 @@ impl ChatMessage {
         driver: &WebDriver,
         chat_id: String,
         last: bool,
+        selectors: &Selectors,
     ) -> WebDriverResult<Vec<Self>> {
+        let rows = Self::rows(driver, chat_id, last, selectors).await?;
+        Ok(rows.into_iter().map(|(_, m)| m).collect())
+    }
+
+    /// Finds the newest message on screen that the reference points at, with its row
+    pub async fn find(
+        driver: &WebDriver,
+        chat_id: String,
+        target: &MessageRef,
+        selectors: &Selectors,
+    ) -> WebDriverResult<Option<(WebElement, Self)>> {
+        let rows = Self::rows(driver, chat_id, false, selectors).await?;
+        Ok(rows.into_iter().rev().find(|(_, m)| target.matches(m)))
+    }
+
+    /// The messages in the current chat along with their row elements
+    async fn rows(
+        driver: &WebDriver,
+        chat_id: String,
+        last: bool,
+        selectors: &Selectors,
+    ) -> WebDriverResult<Vec<(WebElement, Self)>> {
         // Get the chat container
         let chat_container = driver
-            .query(By::XPath(
-                "//div[contains(@aria-label, 'conversation') and @role='grid']",
-            ))
+            .locate(&selectors.conversation_grid)
             .wait(Duration::from_secs(2), Duration::from_millis(100))
             .first()
             .await?;
@@ -98,7 +273,9 @@ impl ChatMessage {
         let messages = loop {
             debug!("Getting chat messages from container");
             let messages = chat_container
-                .find_all(By::XPath(".//div[@class='x78zum5 xdt5ytf']"))
+                .locate(&selectors.message_row)
+                .nowait()
+                .all()
                 .await?;
             if messages.len() > 13 || tries > 5 {
                 if last && !messages.is_empty() {
@@ -114,102 +291,240 @@ impl ChatMessage {
             warn!("Collected no messages!");
         }
 
-        let mut res = Vec::new();
-        let mut homeless = Vec::new();
-        for message in messages {
-            match message
-                .query(By::XPath(
-                    ".//div[@class='html-div xexx8yu x4uap5 x18d9i69 xkhd6sd x1gslohp x11i5rnm x12nagc x1mh8g0r x1yc453h x126k92a x18lvrbx']",
-                ))
-                .wait(Duration::from_millis(15), Duration::from_millis(5))
-                .first()
-                .await
-            {
-                Ok(c) => {
-                    let content = c.text().await?;
-                    
-                    let sender = match message.query(By::XPath(".//img[@class='x1rg5ohu x5yr21d xl1xv1r xh8yej3']"))
-                    .wait(Duration::from_millis(15), Duration::from_millis(5))
-                    .first().await {
-                        Ok(c) => c.attr("alt").await?.unwrap(),
-                        Err(e) => {
-                            // If the same user sends a message twice in a row,
-                            // there will be no sender detected in the HTML.
-                            // Store the messages in the homeless camp until we get one.
-                            homeless.push(content);
-                            debug!("Unable to get sender from the image alt: {e:?}");
-                            continue;
-                        },
-                    };
-
-                    // We have a sender for the homeless messages
-                    for h in homeless.drain(..) {
-                        res.push(Self {
-                            sender: sender.clone(),
-                            content: h,
-                            chat_id: chat_id.clone()
-                        })
-                    }
-
-                    res.push(Self {
-                        sender,
-                        content,
-                        chat_id: chat_id.clone(),
-                    });
+        // Everything else about the rows is read in one go, since each lookup is a round trip
+        let args = vec![
+            Value::Array(
+                messages
+                    .iter()
+                    .map(WebElement::to_json)
+                    .collect::<WebDriverResult<_>>()?,
+            ),
+            serde_json::to_value(selectors).unwrap(),
+            serde_json::to_value(ID_ATTRIBUTES).unwrap(),
+            serde_json::to_value(TIME_ATTRIBUTES).unwrap(),
+        ];
+        let scraped: Vec<Row> = driver
+            .execute(include_str!("scrape_messages.js"), args)
+            .await?
+            .convert()?;
+
+        let mut res: Vec<(WebElement, Self)> = Vec::new();
+        let mut homeless: Vec<(WebElement, Self)> = Vec::new();
+        for (message, row) in messages.into_iter().zip(scraped) {
+            let Some(mut msg) = row.message(&chat_id) else {
+                continue;
+            };
+
+            if msg.from_bot {
+                // Our own messages never have an avatar
+                msg.sender = OWN_SENDER.to_string();
+                if homeless.is_empty() {
+                    res.push((message, msg));
+                } else {
+                    homeless.push((message, msg));
                 }
-                Err(e) => {
-                    // Check if the message is a single emoji
-                    debug!("Unable to get message from the element! {e:?}");
-
-                        match message.query(By::XPath(".//img[@class='xz74otr']")).wait(Duration::from_millis(15), Duration::from_millis(5)).first().await {
-                            Ok(o) => {
-                                if let Ok(Some(attr)) = o.attr("alt").await {
-                                    let content = attr.chars().filter(|&c| c != '\u{fe0f}').collect();
-                                    let sender = match message.query(By::XPath(".//img[@class='x1rg5ohu x5yr21d xl1xv1r xh8yej3']"))
-                                    .wait(Duration::from_millis(15), Duration::from_millis(5))
-                                    .first().await {
-                                        Ok(c) => c.attr("alt").await?.unwrap(),
-                                        Err(e) => {
-                                            homeless.push(content);
-                                            debug!("Unable to get sender from the image alt: {e:?}");
-                                            continue;
-                                        },
-                                    };
-                                    for h in homeless.drain(..) {
-                                        res.push(Self {
-                                            sender: sender.clone(),
-                                            content: h,
-                                            chat_id: chat_id.clone()
-                                        })
-                                    }
-                                    res.push(Self {
-                                        sender,
-                                        content,
-                                        chat_id: chat_id.clone(),
-                                    });
-                                } else {
-                                    debug!("Emoji object has no attribute");
-                                }
-                            }
-                            Err(e) => {
-                                debug!("No emoji object on message: {e:?}");
-                            }
-                        }
-
-                    continue;
+                continue;
+            }
+
+            let Some(sender) = row.avatar else {
+                // If the same user sends a message twice in a row,
+                // there will be no sender detected in the HTML.
+                // Store the messages in the homeless camp until we get one.
+                debug!("Unable to get sender from the image alt");
+                homeless.push((message, msg));
+                continue;
+            };
+
+            // We have a sender for the homeless messages
+            for (row, mut h) in homeless.drain(..) {
+                if !h.from_bot {
+                    h.sender = sender.clone();
                 }
+                res.push((row, h));
+            }
+
+            msg.sender = sender;
+            res.push((message, msg));
+        }
+
+        // Point replies at the quoted message if it's still on screen
+        for i in 0..res.len() {
+            let Some(quoted) = res[i].1.reply_to.as_ref().map(|r| r.content.clone()) else {
+                continue;
             };
+            if let Some((_, target)) = res[..i].iter().rev().find(|(_, m)| m.content == quoted) {
+                let (id, sender) = (target.id.clone(), target.sender.clone());
+                let reply = res[i].1.reply_to.as_mut().unwrap();
+                reply.id = id;
+                reply.sender = Some(sender);
+            }
         }
 
         Ok(res)
     }
+}
+
+/// What scrape_messages.js found in a message row, before any of it is parsed
+#[derive(Debug, Deserialize)]
+struct Row {
+    body: Option<String>,
+    /// The alt text of a message that's only an emoji
+    emoji: Option<String>,
+    /// The text left behind by an unsent message
+    placeholder: Option<String>,
+    own: bool,
+    avatar: Option<String>,
+    /// Candidate ids, in `ID_ATTRIBUTES` order
+    ids: Vec<String>,
+    /// Candidate times, from attributes first and then the text
+    times: Vec<String>,
+    quote: Option<String>,
+    attachments: Vec<RawAttachment>,
+    reactions: Vec<RawReaction>,
+    edited: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawAttachment {
+    #[serde(rename = "type")]
+    kind: AttachmentKind,
+    url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawReaction {
+    /// Like `Alice reacted with 👍`
+    label: Option<String>,
+    /// Alt text of the images inside, used when there's no label
+    alts: Vec<Option<String>>,
+}
+
+impl Row {
+    /// The message in the row, without a sender. Rows without text or attachments are skipped.
+    fn message(&self, chat_id: &str) -> Option<ChatMessage> {
+        let attachments = self.attachments();
+        let content = match (&self.placeholder, &self.body, &self.emoji) {
+            (Some(placeholder), _, _) => placeholder.clone(),
+            (None, Some(body), _) => body.clone(),
+            (None, None, Some(emoji)) => emoji.chars().filter(|&c| c != '\u{fe0f}').collect(),
+            // Photos and files can come without any text
+            _ if !attachments.is_empty() => String::new(),
+            _ => return None,
+        };
+        Some(ChatMessage {
+            sender: String::new(),
+            content,
+            chat_id: chat_id.to_string(),
+            id: self.ids.first().cloned(),
+            timestamp: self.times.iter().find_map(|t| parse_time(t)),
+            from_bot: self.own,
+            reply_to: self.quote.as_ref().map(|quote| ReplyTarget {
+                id: None,
+                sender: None,
+                content: quote
+                    .trim()
+                    .trim_start_matches("Original message:")
+                    .trim()
+                    .to_string(),
+            }),
+            attachments,
+            reactions: self.reactions(),
+            edited: self.edited,
+            unsent: self.placeholder.is_some(),
+        })
+    }
 
-    /// Removes special characters that can't be sent into Messenger
-    pub fn clean(&mut self) {
-        self.content = unidecode::unidecode(&self.content);
+    /// Attachments with a URL, each only once
+    fn attachments(&self) -> Vec<Attachment> {
+        let mut res: Vec<Attachment> = Vec::new();
+        for raw in &self.attachments {
+            let Some(url) = raw.url.as_ref().filter(|u| !u.is_empty()) else {
+                continue;
+            };
+            if res.iter().any(|a| a.url == *url) {
+                continue;
+            }
+            res.push(Attachment {
+                kind: raw.kind,
+                path: None,
+                mime: None,
+                size: None,
+                url: url.clone(),
+            });
+        }
+        res
+    }
+
+    /// Each reaction is read from its label, or just the emoji if there's no label
+    fn reactions(&self) -> Vec<Reaction> {
+        let mut res = Vec::new();
+        for raw in &self.reactions {
+            let label = raw.label.as_deref().unwrap_or_default();
+            if let Some((sender, emoji)) = label.split_once(" reacted with ") {
+                res.push(Reaction {
+                    emoji: normalize_emoji(emoji),
+                    sender: Some(sender.trim().to_string()),
+                });
+                continue;
+            }
+            for alt in raw.alts.iter().flatten().filter(|a| !a.is_empty()) {
+                res.push(Reaction {
+                    emoji: normalize_emoji(alt),
+                    sender: None,
+                });
+            }
+        }
+        res
     }
 }
 
+/// Trims an emoji and drops the variation selector, which Messenger isn't consistent about
+pub fn normalize_emoji(emoji: &str) -> String {
+    emoji
+        .trim()
+        .trim_end_matches(['.', ','])
+        .chars()
+        .filter(|&c| c != '\u{fe0f}')
+        .collect()
+}
+
+/// Reads a timestamp the way Messenger might write it: Unix seconds or milliseconds,
+/// RFC 3339, or a time of day today like `14:05` or `2:05 PM`
+fn parse_time(text: &str) -> Option<i64> {
+    let text = text.trim();
+    if let Ok(n) = text.parse::<i64>() {
+        return Some(if n > 1_000_000_000_000 { n } else { n * 1000 });
+    }
+    if let Ok(t) = chrono::DateTime::parse_from_rfc3339(text) {
+        return Some(t.timestamp_millis());
+    }
+    let time = ["%H:%M", "%I:%M %p"]
+        .iter()
+        .find_map(|f| chrono::NaiveTime::parse_from_str(text, f).ok())?;
+    chrono::Local::now()
+        .date_naive()
+        .and_time(time)
+        .and_local_timezone(chrono::Local)
+        .single()
+        .map(|t| t.timestamp_millis())
+}
+
+impl PartialEq for ChatMessage {
+    fn eq(&self, other: &Self) -> bool {
+        self.sender == other.sender
+            && self.content == other.content
+            && self.chat_id == other.chat_id
+            && self.id == other.id
+            && self.from_bot == other.from_bot
+            && self.reply_to == other.reply_to
+            && self.attachments == other.attachments
+            && self.edited == other.edited
+            && self.unsent == other.unsent
+    }
+}
+
+impl Eq for ChatMessage {}
+
 impl Debug for ChatOption {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Chat")
@@ -231,6 +546,150 @@ impl Debug for ChatMessage {
             .field("sdr", &self.sender)
             .field("msg", &msg)
             .field("chat_id", &self.chat_id)
+            .field("id", &self.id)
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(content: &str, timestamp: Option<i64>) -> ChatMessage {
+        ChatMessage {
+            sender: "Alice".to_string(),
+            content: content.to_string(),
+            chat_id: "100".to_string(),
+            id: None,
+            timestamp,
+            from_bot: false,
+            reply_to: None,
+            attachments: Vec::new(),
+            reactions: Vec::new(),
+            edited: false,
+            unsent: false,
+        }
+    }
+
+    #[test]
+    fn timestamps_and_reactions_dont_make_messages_different() {
+        // "14:05" read just before and just after midnight
+        let before = message("hi", parse_time("14:05"));
+        let mut after = message("hi", before.timestamp.map(|t| t + 86_400_000));
+        after.reactions.push(Reaction {
+            emoji: "👍".to_string(),
+            sender: None,
+        });
+        assert_eq!(before, after);
+        assert_ne!(before, message("hi!", before.timestamp));
+    }
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(parse_time("1760691600"), Some(1760691600000));
+        assert_eq!(parse_time("1760691600000"), Some(1760691600000));
+        assert_eq!(parse_time("2025-10-17T09:00:00+00:00"), Some(1760691600000));
+        let today = chrono::Local::now().date_naive();
+        let at = |t: i64| {
+            chrono::DateTime::from_timestamp_millis(t)
+                .unwrap()
+                .with_timezone(&chrono::Local)
+        };
+        let afternoon = at(parse_time("2:05 PM").unwrap());
+        assert_eq!(afternoon.date_naive(), today);
+        assert_eq!(
+            afternoon.time(),
+            chrono::NaiveTime::from_hms_opt(14, 5, 0).unwrap()
+        );
+        assert_eq!(parse_time("14:05"), parse_time("2:05 PM"));
+        assert_eq!(parse_time("Yesterday"), None);
+    }
+
+    fn row(json: Value) -> Row {
+        let mut row = serde_json::json!({
+            "body": null, "emoji": null, "placeholder": null, "own": false, "avatar": null,
+            "ids": [], "times": [], "quote": null, "attachments": [], "reactions": [], "edited": false
+        });
+        row.as_object_mut()
+            .unwrap()
+            .extend(json.as_object().unwrap().clone());
+        serde_json::from_value(row).unwrap()
+    }
+
+    #[test]
+    fn parses_scraped_rows() {
+        let m = row(serde_json::json!({
+            "body": "pong",
+            "own": true,
+            "ids": ["mid.1", "mid.2"],
+            "times": ["not a time", "1760691600"],
+            "quote": "Original message:\nping",
+            "reactions": [
+                {"label": "Alice reacted with ❤️", "alts": []},
+                {"label": null, "alts": ["👍", null, ""]}
+            ],
+            "edited": true
+        }))
+        .message("100")
+        .unwrap();
+        assert_eq!(m.content, "pong");
+        assert_eq!(m.chat_id, "100");
+        assert_eq!(m.id.as_deref(), Some("mid.1"));
+        assert_eq!(m.timestamp, Some(1760691600000));
+        assert!(m.from_bot && m.edited && !m.unsent);
+        assert_eq!(m.reply_to.unwrap().content, "ping");
+        assert_eq!(
+            m.reactions,
+            vec![
+                Reaction {
+                    emoji: "❤".to_string(),
+                    sender: Some("Alice".to_string()),
+                },
+                Reaction {
+                    emoji: "👍".to_string(),
+                    sender: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_rows_without_text() {
+        // A lone emoji
+        let m = row(serde_json::json!({"emoji": "❤️"}))
+            .message("100")
+            .unwrap();
+        assert_eq!(m.content, "❤");
+
+        // The placeholder wins over whatever else is in the row
+        let m = row(serde_json::json!({"body": "hi", "placeholder": "Alice unsent a message"}))
+            .message("100")
+            .unwrap();
+        assert_eq!(m.content, "Alice unsent a message");
+        assert!(m.unsent);
+
+        // Attachments are kept once each, and ones without a URL are dropped
+        let m = row(serde_json::json!({"attachments": [
+            {"type": "sticker", "url": "https://cdn/s.png"},
+            {"type": "image", "url": "https://cdn/s.png"},
+            {"type": "file", "url": null},
+            {"type": "video", "url": "blob:https://www.messenger.com/1"}
+        ]}))
+        .message("100")
+        .unwrap();
+        assert_eq!(m.content, "");
+        let kinds: Vec<AttachmentKind> = m.attachments.iter().map(|a| a.kind).collect();
+        assert_eq!(kinds, vec![AttachmentKind::Sticker, AttachmentKind::Video]);
+
+        // Nothing worth reading
+        assert!(row(serde_json::json!({"avatar": "Alice"}))
+            .message("100")
+            .is_none());
+    }
+
+    #[test]
+    fn normalizes_emoji() {
+        assert_eq!(normalize_emoji(" ❤️. "), "❤");
+        assert_eq!(normalize_emoji("👍"), "👍");
+    }
+}
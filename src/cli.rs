@@ -0,0 +1,199 @@
+// Jackson Coxson
+// Command line interface.
+// `holly` with no command runs the bot like it always has. The other commands
+// set up or check the config, or talk to a running Holly over its TCP socket
+// the same way a child does.
+
+use clap::{Parser, Subcommand};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    config::{Source, Tcp},
+    framing::{self, FrameDecoder},
+    protocol::{Command, Envelope, Event, Priority, PROTOCOL_VERSION},
+};
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Reads and sends Facebook Messenger messages for child processes"
+)]
+pub struct Cli {
+    /// The config file
+    #[arg(
+        short,
+        long,
+        global = true,
+        env = "HOLLY_CONFIG_PATH",
+        default_value = "config.toml"
+    )]
+    pub config: String,
+    /// Overrides a config key, like `--set gecko.headless=false`. Can be given more than once.
+    #[arg(short, long = "set", global = true, value_name = "KEY=VALUE")]
+    pub set: Vec<String>,
+    #[command(subcommand)]
+    pub command: Option<Cmd>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Cmd {
+    /// Runs Holly. This is the default.
+    Run,
+    /// Creates a config file, asking for each setting when run in a terminal
+    Init {
+        /// Write the default config without asking anything
+        #[arg(long)]
+        defaults: bool,
+        /// Replace an existing config file
+        #[arg(long)]
+        force: bool,
+    },
+    /// Checks the config and selectors files, then exits
+    CheckConfig,
+    /// Upgrades an older config file to the current layout, keeping a backup
+    MigrateConfig,
+    /// Logs in, saves the session and exits
+    LoginOnly,
+    /// Checks the scrapers against recorded pages
+    Fixtures {
+        /// Folder with the recorded pages
+        #[arg(default_value = "fixtures")]
+        dir: String,
+    },
+    /// Has a running Holly save a screenshot to logs/
+    Screenshot,
+    /// Has a running Holly save the page HTML to logs/
+    Html,
+    /// Sends a message through a running Holly
+    Send {
+        /// The chat to send into
+        #[arg(long)]
+        chat: String,
+        /// Send each line of the message separately
+        #[arg(long)]
+        split: bool,
+        /// The id of a message to reply to
+        #[arg(long, value_name = "MESSAGE_ID")]
+        reply_to: Option<String>,
+        /// The message. Several words are joined with spaces.
+        #[arg(required = true)]
+        text: Vec<String>,
+    },
+    /// Reacts to a message through a running Holly
+    React {
+        /// The chat the message is in
+        #[arg(long)]
+        chat: String,
+        /// The id of the message
+        #[arg(long)]
+        message: String,
+        /// Take the reaction off instead
+        #[arg(long)]
+        remove: bool,
+        emoji: String,
+    },
+    /// Answers a two-factor prompt a running Holly is waiting on
+    LoginCode { code: String },
+    /// Has a running Holly reread its config file
+    ReloadConfig,
+}
+
+impl Cli {
+    /// Where the config comes from
+    pub fn source(&self) -> Source {
+        Source {
+            path: self.config.clone(),
+            overrides: self.set.clone(),
+        }
+    }
+}
+
+/// Connects to a running Holly and carries out one command, waiting until it's done
+pub async fn remote(tcp: &Tcp, command: Command) -> Result<(), String> {
+    let host = match tcp.host.as_str() {
+        "0.0.0.0" => "127.0.0.1",
+        host => host,
+    };
+    let mut stream = TcpStream::connect((host, tcp.port))
+        .await
+        .map_err(|e| format!("Unable to connect to Holly at {host}:{}: {e}", tcp.port))?;
+    let mut decoder = FrameDecoder::new(tcp.framing, tcp.max_frame_size);
+    let mut events = Vec::new();
+
+    write(&mut stream, tcp, None, Command::Hello).await?;
+    match next_event(&mut stream, &mut decoder, &mut events).await? {
+        Event::Welcome { version } if version >= 2 => {}
+        event => return Err(format!("Expected a welcome from Holly, got {event:?}")),
+    }
+
+    // Holly remembers finished request ids across restarts, so this has to be unique for good,
+    // not just among the processes running now
+    let request_id = format!(
+        "cli-{}-{}-{:08x}",
+        std::process::id(),
+        chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+        rand::random::<u32>()
+    );
+    write(&mut stream, tcp, Some(request_id.clone()), command).await?;
+    loop {
+        match next_event(&mut stream, &mut decoder, &mut events).await? {
+            Event::Sent { request_id: id } if id == request_id => return Ok(()),
+            Event::Failed {
+                request_id: id,
+                error,
+                reason,
+            } if id == request_id => return Err(format!("{error}: {reason}")),
+            Event::Error { reason } => return Err(reason),
+            _ => {}
+        }
+    }
+}
+
+async fn write(
+    stream: &mut TcpStream,
+    tcp: &Tcp,
+    request_id: Option<String>,
+    command: Command,
+) -> Result<(), String> {
+    let envelope = Envelope {
+        v: PROTOCOL_VERSION,
+        request_id,
+        priority: Priority::Normal,
+        body: command,
+    };
+    let frame = serde_json::to_string(&envelope).unwrap();
+    stream
+        .write_all(&framing::encode(tcp.framing, &frame))
+        .await
+        .map_err(|e| format!("Unable to write to Holly: {e}"))
+}
+
+/// Reads until there's an event, skipping frames that aren't one
+async fn next_event(
+    stream: &mut TcpStream,
+    decoder: &mut FrameDecoder,
+    events: &mut Vec<Event>,
+) -> Result<Event, String> {
+    loop {
+        if !events.is_empty() {
+            return Ok(events.remove(0));
+        }
+        let mut buf = [0; 4096];
+        let n = stream
+            .read(&mut buf)
+            .await
+            .map_err(|e| format!("Unable to read from Holly: {e}"))?;
+        if n == 0 {
+            return Err("Holly closed the connection".to_string());
+        }
+        // Parsed without the envelope, since `sent` and `failed` have a `request_id` of their own
+        for frame in decoder.push(&buf[..n]).into_iter().flatten() {
+            if let Ok(event) = serde_json::from_str::<Event>(&frame) {
+                events.push(event);
+            }
+        }
+    }
+}
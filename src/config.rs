@@ -1,183 +1,889 @@
 // Jackson Coxson
 
 use dialoguer::{theme::ColorfulTheme, Input, Password, Select};
+use std::collections::BTreeMap;
+
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{framing::Framing, secrets, totp, typing::Typing};
+
+/// The layout of the config file Holly writes. Files without a `config_version` are version 1,
+/// and are migrated up to this when they're loaded.
+pub const CONFIG_VERSION: u32 = 2;
+
+/// Keys that are read whenever they're needed, so reloading the config applies them straight away
+const LIVE_KEYS: &[&str] = &[
+    "fb_username",
+    "fb_password",
+    "e2ee_pin",
+    "totp_secret",
+    "refresh_rate",
+    "latency",
+    "selectors",
+    "typing",
+    "media",
+    "health",
+    "queue.max_attempts",
+    "queue.retry_delay",
+    "queue.batch_size",
+];
+
+/// Keys that are only read when the process starts, not when the browser restarts
+const LAUNCH_KEYS: &[&str] = &["tcp", "queue.path"];
 
 const DEFAULT_CONFIG: &str = r#"# Holly Config
+config_version = 2
 fb_username = "asdfasdf@urmom.com"
 fb_password = "monkey123"
 refresh_rate = 3000
+latency = 1000
+browser = "firefox"
+selectors = "selectors.toml"
 
 [gecko]
 port = 4444
 path = "/home/user/gecko"
 headless = true
+# The browser's own executable, if the driver can't find it
+# binary = "/usr/bin/chromium"
+# Keep the browser profile here between runs instead of saving cookies
+# profile = "profile"
+# Connect to a WebDriver that's already running, such as a Selenium container
+# url = "http://localhost:4444"
+# spawn = false
+connect_attempts = 10
+connect_delay = 500
 
 [tcp]
 port = 8011
 host = "127.0.0.1"
+framing = "ndjson"
+max_frame_size = 1048576
+
+# How messages are typed: "type" a key at a time, or "paste" them in at once
+[typing]
+mode = "type"
+wpm = 600
+jitter = 0.3
+typo_rate = 0.03
+# Replace accents, emoji and other scripts with the closest ASCII
+transliterate = false
+
+# Overrides for one chat
+# [typing.chats.1234567890]
+# mode = "paste"
+
+[queue]
+path = "queue.jsonl"
+max_attempts = 5
+retry_delay = 2000
+batch_size = 10
+
+[health]
+interval = 60000
+
+# Photos, videos, voice clips and files on incoming messages are saved here
+[media]
+dir = "media"
+download = true
+max_size = 26214400
+
+# Credentials can also come from HOLLY_FB_USERNAME, HOLLY_FB_PASSWORD, HOLLY_E2EE_PIN and HOLLY_TOTP_SECRET,
+# files named by HOLLY_<NAME>_FILE, or files named fb_password etc. in `dir`
+# or in systemd's $CREDENTIALS_DIRECTORY.
+# Setting HOLLY_PASSPHRASE (or a passphrase file) or `key_file` encrypts cookies.json and storage.json.
+[secrets]
+# dir = "/run/secrets"
+# key_file = "holly.key"
 "#;
 
-/// Holly configuration file
+/// Holly configuration file. Every key is optional.
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
+    pub config_version: u32,
     pub fb_username: String,
     pub fb_password: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub e2ee_pin: Option<String>,
+    /// Base32 secret from setting up an authenticator app, to answer two-factor prompts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub totp_secret: Option<String>,
     pub refresh_rate: usize,
     pub latency: usize,
+    pub browser: BrowserKind,
+    /// The WebDriver for `browser`. Also accepted as `[webdriver]`.
+    #[serde(alias = "webdriver")]
     pub gecko: Gecko,
     pub tcp: Tcp,
+    /// TOML file with the Messenger element locators. The built-in ones are used if it's missing.
+    pub selectors: String,
+    /// How messages are typed
+    pub typing: Typing,
+    pub queue: Queue,
+    pub health: Health,
+    /// Where attachments on incoming messages are saved
+    pub media: Media,
+    pub secrets: Secrets,
+    /// What Holly talks to. The mock backend runs a script instead of a browser.
+    pub backend: Backend,
+    pub mock: Mock,
+    /// Keys in the file Holly doesn't know, which are probably typos
+    #[serde(skip)]
+    pub unknown_keys: Vec<String>,
+    /// Where the config was loaded from, to reload it
+    #[serde(skip)]
+    pub source: Source,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            config_version: CONFIG_VERSION,
+            fb_username: String::new(),
+            fb_password: String::new(),
+            e2ee_pin: None,
+            totp_secret: None,
+            refresh_rate: 3000,
+            latency: 1000,
+            browser: BrowserKind::default(),
+            gecko: Gecko::default(),
+            tcp: Tcp::default(),
+            selectors: "selectors.toml".to_string(),
+            typing: Typing::default(),
+            queue: Queue::default(),
+            health: Health::default(),
+            media: Media::default(),
+            secrets: Secrets::default(),
+            backend: Backend::default(),
+            mock: Mock::default(),
+            unknown_keys: Vec::new(),
+            source: Source::default(),
+        }
+    }
 }
 
+/// The browser to drive. Firefox uses geckodriver, Chromium uses chromedriver.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BrowserKind {
+    #[default]
+    Firefox,
+    Chromium,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Browser,
+    Mock,
+}
+
+/// Offline backend for testing
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Mock {
+    /// JSON file with the chats and messages to play back
+    pub script: String,
+}
+
+impl Default for Mock {
+    fn default() -> Self {
+        Self {
+            script: "mock.json".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Gecko {
     pub port: u16,
+    /// The driver executable. Defaults to geckodriver or chromedriver on the PATH.
     pub path: String,
     pub headless: bool,
+    /// Path to the browser itself, if the driver can't find it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub binary: Option<String>,
+    /// Folder the browser keeps its profile in between runs, so the session and E2EE keys survive restarts.
+    /// Without one, Holly saves and restores cookies and storage itself.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// Overrides the browser's user agent. Chromium uses Holly's built-in one by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    /// The WebDriver to connect to. Defaults to the spawned driver on `port`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Whether Holly starts the driver itself. Turn off to use one that's already running.
+    pub spawn: bool,
+    /// How many times to try connecting to the WebDriver before giving up
+    pub connect_attempts: u32,
+    /// Milliseconds between connection attempts
+    pub connect_delay: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl Default for Gecko {
+    fn default() -> Self {
+        Self {
+            port: 4444,
+            path: String::new(),
+            headless: true,
+            binary: None,
+            profile: None,
+            user_agent: None,
+            url: None,
+            spawn: true,
+            connect_attempts: 10,
+            connect_delay: 500,
+        }
+    }
+}
+
+impl Gecko {
+    /// The URL of the WebDriver to connect to
+    pub fn url(&self) -> String {
+        self.url
+            .clone()
+            .unwrap_or_else(|| format!("http://localhost:{}", self.port))
+    }
+
+    /// The driver to spawn for the browser
+    pub fn driver_path(&self, browser: BrowserKind) -> &str {
+        match (self.path.as_str(), browser) {
+            ("", BrowserKind::Firefox) => "geckodriver",
+            ("", BrowserKind::Chromium) => "chromedriver",
+            (path, _) => path,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Tcp {
     pub port: u16,
     pub host: String,
+    /// How messages are delimited on the socket. Use "concatenated" for old holly.py clients
+    pub framing: Framing,
+    /// The largest single message a child may send, in bytes
+    pub max_frame_size: usize,
+}
+
+impl Default for Tcp {
+    fn default() -> Self {
+        Self {
+            port: 8011,
+            host: "127.0.0.1".to_string(),
+            framing: Framing::default(),
+            max_frame_size: 1024 * 1024,
+        }
+    }
+}
+
+/// Outbound message queue
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Queue {
+    /// The journal file pending messages are kept in
+    pub path: String,
+    /// How many times to try a message before giving up
+    pub max_attempts: u32,
+    /// Milliseconds to wait before the first retry. Doubles with every attempt.
+    pub retry_delay: u64,
+    /// The most messages to send before going back to reading chats
+    pub batch_size: usize,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Self {
+            path: "queue.jsonl".to_string(),
+            max_attempts: 5,
+            retry_delay: 2000,
+            batch_size: 10,
+        }
+    }
+}
+
+/// Selector self-check
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Health {
+    /// Milliseconds between checks of the selectors against the live page. 0 turns them off.
+    pub interval: u64,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self { interval: 60000 }
+    }
+}
+
+/// Attachment downloads
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Media {
+    pub dir: String,
+    /// Whether to download attachments at all. Children still get their URLs if not.
+    pub download: bool,
+    /// Largest attachment to download, in bytes
+    pub max_size: u64,
+}
+
+impl Default for Media {
+    fn default() -> Self {
+        Self {
+            dir: "media".to_string(),
+            download: true,
+            max_size: 25 * 1024 * 1024,
+        }
+    }
+}
+
+/// Where credentials and the session encryption key come from
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Secrets {
+    /// Folder with one file per secret, such as /run/secrets for Docker
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dir: Option<String>,
+    /// File whose contents encrypt the session files, if there's no passphrase
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<String>,
+}
+
+/// Where the config comes from
+#[derive(Debug, Clone, Default)]
+pub struct Source {
+    pub path: String,
+    /// `key=value` pairs from the command line that win over the file.
+    /// Nested keys are dotted, like `gecko.headless=false`.
+    pub overrides: Vec<String>,
 }
 
 impl Config {
-    /// Loads the config file
-    pub fn load() -> Self {
-        // Determine if HOLLY_CONFIG_PATH is set
-        let path = std::env::var("HOLLY_CONFIG_PATH").unwrap_or("config.toml".to_string());
-
-        // Load the file or create it
-        match std::fs::read_to_string(&path) {
-            Ok(contents) => toml::from_str(&contents).expect("Invalid config file"),
-            Err(_) => {
-                // Create new configuration file
-                if atty::is(atty::Stream::Stdout) {
-                    println!("Looks like you don't have a Holly config, let's set one up!");
-                    let new_config = Config {
-                        fb_username: Input::with_theme(&ColorfulTheme::default())
-                            .with_prompt("Enter your Facebook bot username")
+    /// Loads the config file, filling in secrets from the environment
+    pub fn load(source: &Source) -> Result<Self, String> {
+        Self::load_checked(source, true)
+    }
+
+    /// Loads the config for commands that never log in, so the Facebook credentials can be left out
+    pub fn load_offline(source: &Source) -> Result<Self, String> {
+        Self::load_checked(source, false)
+    }
+
+    fn load_checked(source: &Source, login: bool) -> Result<Self, String> {
+        let mut config = Self::read(source)?;
+        config.source = source.clone();
+        config.resolve_secrets();
+        config
+            .validate(login)
+            .map_err(|e| format!("Invalid config file {}: {e}", source.path))?;
+        Ok(config)
+    }
+
+    /// Rereads the config from where it was loaded, without applying it.
+    /// Returns the new config and the keys that changed, by when they take effect.
+    pub fn reload(&self) -> Result<(Self, Reload), String> {
+        let new = Self::load(&self.source)?;
+        let old = flatten(serde_json::to_value(self).unwrap());
+        let updated = flatten(serde_json::to_value(&new).unwrap());
+
+        let mut res = Reload::default();
+        let keys: Vec<&String> = old.keys().chain(updated.keys()).collect();
+        for key in keys {
+            if key == "config_version" || old.get(key) == updated.get(key) {
+                continue;
+            }
+            let list = if matches_any(key, LIVE_KEYS) {
+                &mut res.applied
+            } else if matches_any(key, LAUNCH_KEYS) {
+                &mut res.needs_relaunch
+            } else {
+                &mut res.needs_restart
+            };
+            if !list.contains(key) {
+                list.push(key.clone());
+            }
+        }
+        Ok((new, res))
+    }
+
+    /// Secrets from the environment or secret files win over the config file
+    fn resolve_secrets(&mut self) {
+        if let Some(username) = secrets::lookup("fb_username", &self.secrets) {
+            self.fb_username = username;
+        }
+        if let Some(password) = secrets::lookup("fb_password", &self.secrets) {
+            self.fb_password = password;
+        }
+        if let Some(pin) = secrets::lookup("e2ee_pin", &self.secrets) {
+            self.e2ee_pin = Some(pin);
+        }
+        if let Some(totp) = secrets::lookup("totp_secret", &self.secrets) {
+            self.totp_secret = Some(totp);
+        }
+    }
+
+    fn read(source: &Source) -> Result<Self, String> {
+        let path = &source.path;
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            format!("Unable to read config file {path}: {e}. Run `holly init` to create one.")
+        })?;
+        let mut table: toml::Table =
+            toml::from_str(&contents).map_err(|e| format!("Invalid config file {path}: {e}"))?;
+
+        // Only upgraded in memory, `migrate_file` is what rewrites it
+        if let Some(version) = migrate(&mut table).map_err(|e| format!("{path}: {e}"))? {
+            info!("{path} is config version {version}, run `holly migrate-config` to upgrade it to {CONFIG_VERSION}");
+        }
+
+        for o in &source.overrides {
+            apply_override(&mut table, o)?;
+        }
+
+        let mut unknown_keys = Vec::new();
+        let mut track = |key: serde_ignored::Path| unknown_keys.push(key.to_string());
+        let deserializer = serde_ignored::Deserializer::new(toml::Value::Table(table), &mut track);
+        let mut config: Self = serde_path_to_error::deserialize(deserializer).map_err(|e| {
+            format!(
+                "Invalid config file {path}: `{}`: {}",
+                e.path(),
+                e.inner().message()
+            )
+        })?;
+        for key in &unknown_keys {
+            warn!("Unknown key `{key}` in {path}, ignoring it");
+        }
+        config.unknown_keys = unknown_keys;
+        Ok(config)
+    }
+
+    /// Checks the values that parse fine but can't work.
+    /// The credentials are only needed if Holly is going to log in.
+    fn validate(&self, login: bool) -> Result<(), String> {
+        if self.refresh_rate == 0 {
+            return Err("`refresh_rate` must be more than 0".to_string());
+        }
+        if let Some(pin) = &self.e2ee_pin {
+            if pin.is_empty() || !pin.chars().all(|c| c.is_ascii_digit()) {
+                return Err("`e2ee_pin` must be a number".to_string());
+            }
+        }
+        if let Some(secret) = &self.totp_secret {
+            totp::now(secret).map_err(|e| format!("`totp_secret` is invalid: {e}"))?;
+        }
+        if login
+            && self.backend == Backend::Browser
+            && (self.fb_username.is_empty() || self.fb_password.is_empty())
+        {
+            return Err("`fb_username` and `fb_password` must be set".to_string());
+        }
+        if self.gecko.connect_attempts == 0 {
+            return Err("`gecko.connect_attempts` must be at least 1".to_string());
+        }
+        if self.tcp.max_frame_size == 0 {
+            return Err("`tcp.max_frame_size` must be more than 0".to_string());
+        }
+        if self.queue.max_attempts == 0 {
+            return Err("`queue.max_attempts` must be at least 1".to_string());
+        }
+        if self.queue.batch_size == 0 {
+            return Err("`queue.batch_size` must be at least 1".to_string());
+        }
+        self.typing.validate()
+    }
+
+    /// Upgrades the config file to `CONFIG_VERSION` on disk.
+    /// The old file is kept next to it, since rewriting it drops the comments.
+    pub fn migrate_file(path: &str) -> Result<(), String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read config file {path}: {e}"))?;
+        let mut table: toml::Table =
+            toml::from_str(&contents).map_err(|e| format!("Invalid config file {path}: {e}"))?;
+        let Some(version) = migrate(&mut table).map_err(|e| format!("{path}: {e}"))? else {
+            println!("{path} is already config version {CONFIG_VERSION}");
+            return Ok(());
+        };
+
+        let backup = format!("{path}.v{version}.bak");
+        std::fs::copy(path, &backup)
+            .and_then(|_| secrets::write_private(path, toml::to_string(&table).unwrap().as_bytes()))
+            .map_err(|e| format!("Unable to save the migrated config file {path}: {e}"))?;
+        println!("Migrated {path} from version {version} to {CONFIG_VERSION}, the old file is at {backup}");
+        Ok(())
+    }
+
+    /// Creates a new config file. Asks for each setting when run in a terminal,
+    /// otherwise writes the defaults for editing by hand.
+    pub fn init(path: &str, defaults: bool, force: bool) -> Result<(), String> {
+        if !force && std::path::Path::new(path).exists() {
+            return Err(format!("{path} already exists, pass --force to replace it"));
+        }
+        if !defaults && atty::is(atty::Stream::Stdout) {
+            println!("Let's set up Holly!");
+            let browser = if Select::with_theme(&ColorfulTheme::default())
+                .with_prompt("Which browser should Holly use?")
+                .item("Firefox")
+                .item("Chromium")
+                .interact()
+                .unwrap()
+                == 0
+            {
+                BrowserKind::Firefox
+            } else {
+                BrowserKind::Chromium
+            };
+            let (driver, default_port) = match browser {
+                BrowserKind::Firefox => ("geckodriver", "4444"),
+                BrowserKind::Chromium => ("chromedriver", "9515"),
+            };
+            let new_config = Config {
+                fb_username: Input::with_theme(&ColorfulTheme::default())
+                    .with_prompt("Enter your Facebook bot username")
+                    .interact()
+                    .unwrap(),
+                fb_password: Password::with_theme(&ColorfulTheme::default())
+                    .with_prompt("Enter your Facebook bot password")
+                    .interact()
+                    .unwrap(),
+                e2ee_pin: loop {
+                    let pin: String = Input::with_theme(&ColorfulTheme::default())
+                        .with_prompt("Enter your Facebook bot e2ee pin (leave empty for none)")
+                        .allow_empty(true)
+                        .interact()
+                        .unwrap();
+                    if pin.is_empty() {
+                        break None;
+                    } else if pin.parse::<u32>().is_ok() {
+                        break Some(pin);
+                    }
+                    println!("Enter a number...");
+                },
+                refresh_rate: loop {
+                    let rate: String = Input::with_theme(&ColorfulTheme::default())
+                        .with_prompt(
+                            "Choose a refresh rate in miliseconds for message scanning. A higher refresh rate will mean faster message reading, but will increase CPU usage and accuracy.",
+                        )
+                        .default("3000".to_string())
+                        .interact()
+                        .unwrap();
+                    if let Ok(rate) = rate.parse::<usize>() {
+                        break rate;
+                    }
+                    println!("Enter a number...");
+                },
+                latency: loop {
+                    let rate: String = Input::with_theme(&ColorfulTheme::default())
+                        .with_prompt(
+                            "Enter the latency on context changes. Holly will be quicker with a lower latency, but less accurate with poor hardware and network speed.",
+                        )
+                        .default("1000".to_string())
+                        .interact()
+                        .unwrap();
+                    if let Ok(rate) = rate.parse::<usize>() {
+                        break rate;
+                    }
+                    println!("Enter a number...");
+                },
+                browser,
+                gecko: Gecko {
+                    port: loop {
+                        let rate: String = Input::with_theme(&ColorfulTheme::default())
+                        .with_prompt(format!(
+                            "Enter the port that {driver} is listening on. Defaults to {default_port}.",
+                        ))
+                        .default(default_port.to_string())
+                        .interact()
+                        .unwrap();
+                        if let Ok(rate) = rate.parse::<u16>() {
+                            break rate;
+                        }
+                        println!("Enter a number...");
+                    },
+                    path: {
+                        match browser {
+                            BrowserKind::Firefox => println!("Get geckodriver at https://github.com/mozilla/geckodriver/releases. Unzip it and place the file where this program can find it."),
+                            BrowserKind::Chromium => println!("Get chromedriver from your package manager (it's often packaged with Chromium) or https://googlechromelabs.github.io/chrome-for-testing/. It must match your Chromium version."),
+                        }
+                        println!(
+                            "You need {} for {}.",
+                            std::env::consts::ARCH,
+                            std::env::consts::OS
+                        );
+                        Input::with_theme(&ColorfulTheme::default())
+                            .with_prompt(format!("Enter the path to {driver}"))
+                            .default(driver.to_string())
+                            .interact()
+                            .unwrap()
+                    },
+                    headless: Select::with_theme(&ColorfulTheme::default())
+                        .with_prompt("Headless? (don't show the browser window)")
+                        .item("Yes")
+                        .item("No")
+                        .interact()
+                        .unwrap()
+                        == 0,
+                    ..Gecko::default()
+                },
+                tcp: Tcp {
+                    port: loop {
+                        let port: String = Input::with_theme(&ColorfulTheme::default())
+                            .with_prompt("Choose a port to listen for children processes on")
+                            .default("8011".to_string())
                             .interact()
-                            .unwrap(),
-                        fb_password: Password::with_theme(&ColorfulTheme::default())
-                            .with_prompt("Enter your Facebook bot password")
+                            .unwrap();
+                        if let Ok(rate) = port.parse::<u16>() {
+                            break rate;
+                        }
+                        println!("Enter a number...");
+                    },
+                    host: loop {
+                        let ip: String = Input::with_theme(&ColorfulTheme::default())
+                            .with_prompt("Enter the IP to listen on")
+                            .default("127.0.0.1".to_string())
                             .interact()
-                            .unwrap(),
-                        e2ee_pin: loop {
-                            let pin: String = Input::with_theme(&ColorfulTheme::default())
-                                .with_prompt(
-                                    "Enter your Facebook bot e2ee pin (leave empty for none)",
-                                )
-                                .allow_empty(true)
-                                .interact()
-                                .unwrap();
-                            if pin.is_empty() {
-                                break None;
-                            } else if pin.parse::<u32>().is_ok() {
-                                break Some(pin);
-                            }
-                            println!("Enter a number...");
-                        },
-                        refresh_rate: loop {
-                            let rate: String = Input::with_theme(&ColorfulTheme::default())
-                                .with_prompt(
-                                    "Choose a refresh rate in miliseconds for message scanning. A higher refresh rate will mean faster message reading, but will increase CPU usage and accuracy.",
-                                )
-                                .default("3000".to_string())
-                                .interact()
-                                .unwrap();
-                            if let Ok(rate) = rate.parse::<usize>() {
-                                break rate;
-                            }
-                            println!("Enter a number...");
-                        },
-                        latency: loop {
-                            let rate: String = Input::with_theme(&ColorfulTheme::default())
-                                .with_prompt(
-                                    "Enter the latency on context changes. Holly will be quicker with a lower latency, but less accurate with poor hardware and network speed.",
-                                )
-                                .default("1000".to_string())
-                                .interact()
-                                .unwrap();
-                            if let Ok(rate) = rate.parse::<usize>() {
-                                break rate;
-                            }
-                            println!("Enter a number...");
-                        },
-                        gecko: Gecko {
-                            port: loop {
-                                let rate: String = Input::with_theme(&ColorfulTheme::default())
-                                .with_prompt(
-                                    "Enter the port that geckodriver is listening on. Defaults to 4444.",
-                                )
-                                .default("4444".to_string())
-                                .interact()
-                                .unwrap();
-                                if let Ok(rate) = rate.parse::<u16>() {
-                                    break rate;
-                                }
-                                println!("Enter a number...");
-                            },
-                            path: {
-                                println!("Get geckodriver at https://github.com/mozilla/geckodriver/releases. Unzip it and place the file where this program can find it.");
-                                println!(
-                                    "You need {} for {}.",
-                                    std::env::consts::ARCH,
-                                    std::env::consts::OS
-                                );
-                                Input::with_theme(&ColorfulTheme::default())
-                                    .with_prompt("Enter the path to geckodriver")
-                                    .default("geckodriver".to_string())
-                                    .interact()
-                                    .unwrap()
-                            },
-                            headless: Select::with_theme(&ColorfulTheme::default())
-                                .with_prompt("Headless? (don't show the Firefox window)")
-                                .item("Yes")
-                                .item("No")
-                                .interact()
-                                .unwrap()
-                                == 0,
-                        },
-                        tcp: Tcp {
-                            port: loop {
-                                let port: String = Input::with_theme(&ColorfulTheme::default())
-                                    .with_prompt(
-                                        "Choose a port to listen for children processes on",
-                                    )
-                                    .default("8011".to_string())
-                                    .interact()
-                                    .unwrap();
-                                if let Ok(rate) = port.parse::<u16>() {
-                                    break rate;
-                                }
-                                println!("Enter a number...");
-                            },
-                            host: loop {
-                                let ip: String = Input::with_theme(&ColorfulTheme::default())
-                                    .with_prompt("Enter the IP to listen on")
-                                    .default("127.0.0.1".to_string())
-                                    .interact()
-                                    .unwrap();
-                                if ip.parse::<std::net::Ipv4Addr>().is_ok() {
-                                    break ip;
-                                }
-                                println!("Enter an IP address...");
-                            },
-                        },
+                            .unwrap();
+                        if ip.parse::<std::net::Ipv4Addr>().is_ok() {
+                            break ip;
+                        }
+                        println!("Enter an IP address...");
+                    },
+                    ..Tcp::default()
+                },
+                ..Config::default()
+            };
+            secrets::write_private(path, toml::to_string(&new_config).unwrap().as_bytes())
+                .map_err(|e| format!("Unable to write new config file {path}: {e}"))?;
+            println!("Wrote {path}");
+        } else {
+            secrets::write_private(path, DEFAULT_CONFIG.as_bytes())
+                .map_err(|e| format!("Unable to write default config file {path}: {e}"))?;
+            println!("WROTE NEW CONFIG FILE! Edit it at {path}. You probably don't want the default values.");
+        }
+        Ok(())
+    }
+}
+
+/// What changed when the config was reloaded
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Reload {
+    /// Keys that took effect straight away
+    pub applied: Vec<String>,
+    /// Keys that take effect when the browser restarts, such as with the `restart` command
+    pub needs_restart: Vec<String>,
+    /// Keys that take effect when the Holly process is started again
+    pub needs_relaunch: Vec<String>,
+}
+
+/// Whether a dotted key is one of the given keys or inside one of them
+fn matches_any(key: &str, keys: &[&str]) -> bool {
+    keys.iter().any(|k| {
+        key == *k
+            || key
+                .strip_prefix(k)
+                .is_some_and(|rest| rest.starts_with('.'))
+    })
+}
+
+/// Flattens nested tables into dotted keys, so two configs can be compared key by key
+fn flatten(value: Value) -> BTreeMap<String, Value> {
+    fn walk(prefix: String, value: Value, res: &mut BTreeMap<String, Value>) {
+        match value {
+            Value::Object(map) => {
+                for (k, v) in map {
+                    let key = if prefix.is_empty() {
+                        k
+                    } else {
+                        format!("{prefix}.{k}")
                     };
-                    std::fs::write(path, toml::to_string(&new_config).unwrap())
-                        .expect("Unable to write new config file");
-                    new_config
-                } else {
-                    println!("WROTE NEW CONFIG FILE! Edit it at {path}. You probably don't want the default values.");
-                    std::fs::write(path, DEFAULT_CONFIG)
-                        .expect("Unable to write default config file!");
-                    toml::from_str(DEFAULT_CONFIG).unwrap()
+                    walk(key, v, res);
                 }
             }
+            value => {
+                res.insert(prefix, value);
+            }
         }
     }
+    let mut res = BTreeMap::new();
+    walk(String::new(), value, &mut res);
+    res
+}
+
+/// Sets a dotted `key=value` in the parsed config file.
+/// The value is read as TOML if it can be, like `true` or `4444`, and as a string otherwise.
+fn apply_override(table: &mut toml::Table, pair: &str) -> Result<(), String> {
+    let Some((key, value)) = pair.split_once('=') else {
+        return Err(format!("Override {pair:?} should look like key=value"));
+    };
+    let value = match toml::from_str::<toml::Table>(&format!("v = {value}")) {
+        Ok(mut t) => t.remove("v").unwrap(),
+        Err(_) => toml::Value::String(value.to_string()),
+    };
+
+    let mut keys: Vec<&str> = key.trim().split('.').collect();
+    let last = keys.pop().unwrap();
+    let mut table = table;
+    for k in keys {
+        table = table
+            .entry(k)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| format!("Can't override {key}, {k} isn't a table"))?;
+    }
+    if last.is_empty() {
+        return Err(format!("Override {pair:?} has no key"));
+    }
+    table.insert(last.to_string(), value);
+    Ok(())
+}
+
+/// Brings a parsed config file up to `CONFIG_VERSION`.
+/// Returns the version it started at, if it had to change anything.
+fn migrate(table: &mut toml::Table) -> Result<Option<u32>, String> {
+    let version = match table.get("config_version") {
+        None => 1,
+        Some(toml::Value::Integer(v)) if *v >= 1 => *v as u32,
+        Some(v) => {
+            return Err(format!(
+                "`config_version` must be a positive number, not {v}"
+            ))
+        }
+    };
+    if version > CONFIG_VERSION {
+        return Err(format!(
+            "`config_version` is {version}, but this Holly only knows up to {CONFIG_VERSION}"
+        ));
+    }
+    if version == CONFIG_VERSION {
+        return Ok(None);
+    }
+
+    for from in version..CONFIG_VERSION {
+        match from {
+            1 => {
+                // Version 1 predates newline framing, and its children write JSON back to back
+                if let Some(toml::Value::Table(tcp)) = table.get_mut("tcp") {
+                    tcp.entry("framing")
+                        .or_insert_with(|| toml::Value::String("concatenated".to_string()));
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+    table.insert(
+        "config_version".to_string(),
+        toml::Value::Integer(CONFIG_VERSION as i64),
+    );
+    Ok(Some(version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(contents: &str) -> toml::Table {
+        toml::from_str(contents).unwrap()
+    }
+
+    #[test]
+    fn migrates_version_1() {
+        let mut t = table("[tcp]\nport = 8011\n");
+        assert_eq!(migrate(&mut t).unwrap(), Some(1));
+        assert_eq!(
+            t["config_version"].as_integer(),
+            Some(CONFIG_VERSION as i64)
+        );
+        assert_eq!(t["tcp"]["framing"].as_str(), Some("concatenated"));
+
+        // A framing that was set by hand is kept
+        let mut t = table("[tcp]\nframing = \"ndjson\"\n");
+        migrate(&mut t).unwrap();
+        assert_eq!(t["tcp"]["framing"].as_str(), Some("ndjson"));
+
+        // Nothing to do the second time
+        assert_eq!(migrate(&mut t).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        assert!(migrate(&mut table("config_version = 0")).is_err());
+        assert!(migrate(&mut table("config_version = \"2\"")).is_err());
+        let newer = format!("config_version = {}", CONFIG_VERSION + 1);
+        assert!(migrate(&mut table(&newer)).is_err());
+    }
+
+    #[test]
+    fn overrides() {
+        let mut t = table("refresh_rate = 500\n[gecko]\nheadless = true\n");
+        apply_override(&mut t, "gecko.headless=false").unwrap();
+        apply_override(&mut t, "refresh_rate=1000").unwrap();
+        apply_override(&mut t, "fb_username=holly@example.com").unwrap();
+        apply_override(&mut t, "tcp.host=\"0.0.0.0\"").unwrap();
+        apply_override(&mut t, "health.interval = 0").unwrap();
+        assert_eq!(t["gecko"]["headless"].as_bool(), Some(false));
+        assert_eq!(t["refresh_rate"].as_integer(), Some(1000));
+        assert_eq!(t["fb_username"].as_str(), Some("holly@example.com"));
+        assert_eq!(t["tcp"]["host"].as_str(), Some("0.0.0.0"));
+        assert_eq!(t["health"]["interval"].as_integer(), Some(0));
+
+        assert!(apply_override(&mut t, "refresh_rate").is_err());
+        assert!(apply_override(&mut t, "gecko.=1").is_err());
+        assert!(apply_override(&mut t, "refresh_rate.x=1").is_err());
+    }
+
+    #[test]
+    fn offline_loads_dont_need_credentials() {
+        let path =
+            std::env::temp_dir().join(format!("holly-config-offline-{}.toml", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        std::fs::write(&path, format!("config_version = {CONFIG_VERSION}\n")).unwrap();
+        let source = Source {
+            path: path.clone(),
+            overrides: Vec::new(),
+        };
+        assert!(Config::load(&source).unwrap_err().contains("fb_username"));
+        assert!(Config::load_offline(&source).is_ok());
+
+        // Everything else is still checked
+        let source = Source {
+            overrides: vec!["refresh_rate=0".to_string()],
+            ..source
+        };
+        assert!(Config::load_offline(&source).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reading_leaves_the_file_alone() {
+        let path = std::env::temp_dir().join(format!("holly-config-{}.toml", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let contents = "# Mine\nfb_username = \"holly\"\n[tcp]\nport = 8011\n";
+        std::fs::write(&path, contents).unwrap();
+        let source = Source {
+            path: path.clone(),
+            overrides: vec!["tcp.port=8012".to_string()],
+        };
+
+        let config = Config::read(&source).unwrap();
+        assert_eq!(config.tcp.framing, Framing::Concatenated);
+        assert_eq!(config.tcp.port, 8012);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), contents);
+
+        Config::migrate_file(&path).unwrap();
+        let backup = format!("{path}.v1.bak");
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), contents);
+        let migrated = table(&std::fs::read_to_string(&path).unwrap());
+        assert_eq!(
+            migrated["config_version"].as_integer(),
+            Some(CONFIG_VERSION as i64)
+        );
+        assert_eq!(migrated["tcp"]["port"].as_integer(), Some(8011));
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&backup).unwrap();
+    }
 }
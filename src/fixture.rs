@@ -0,0 +1,327 @@
+// Jackson Coxson
+// Offline selector checks against recorded Messenger pages.
+// Each fixture is a page saved with the `html` command (logs/<timestamp>-log.html)
+// copied into a fixtures folder as <name>.html, next to a <name>.json holding
+// what Holly should read from it. The pages are served from a local HTTP server
+// and scraped with the same code that runs against the real Messenger.
+
+use std::path::{Path, PathBuf};
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{browser::Browser, chat::ChatMessage, config::Config};
+
+/// What a fixture is expected to contain
+#[derive(Debug, Serialize, Deserialize)]
+struct Expected {
+    /// The chat the page was recorded in
+    chat_id: String,
+    /// Sidebar chats, if they should be checked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chats: Option<Vec<ExpectedChat>>,
+    /// Messages in the open chat, if they should be checked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    messages: Option<Vec<ChatMessage>>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct ExpectedChat {
+    id: String,
+    unread: bool,
+}
+
+/// Runs every fixture in the folder, returning whether they all passed
+pub async fn run(config: &Config, dir: &str) -> bool {
+    let mut fixtures: Vec<PathBuf> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|e| e == "html"))
+            .collect(),
+        Err(e) => {
+            println!("Unable to read fixtures folder {dir}: {e}");
+            return false;
+        }
+    };
+    fixtures.sort();
+    if fixtures.is_empty() {
+        println!("No fixtures found in {dir}");
+        return false;
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let root = PathBuf::from(dir);
+    tokio::spawn(async move {
+        loop {
+            if let Ok((stream, _)) = listener.accept().await {
+                let root = root.clone();
+                tokio::spawn(async move { serve_page(stream, &root).await });
+            }
+        }
+    });
+    info!("Serving fixtures from {dir} on {addr}");
+
+    let client = match Browser::launch(config).await {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Unable to start the browser: {e}");
+            return false;
+        }
+    };
+    let mut passed = 0;
+    for fixture in &fixtures {
+        let name = fixture.file_stem().unwrap().to_string_lossy().to_string();
+        if check(&client, &addr.to_string(), fixture, &name).await {
+            println!("PASS {name}");
+            passed += 1;
+        } else {
+            println!("FAIL {name}");
+        }
+    }
+    println!("{passed}/{} fixtures passed", fixtures.len());
+    passed == fixtures.len()
+}
+
+/// Scrapes one fixture and compares it with its expectations
+async fn check(client: &Browser, addr: &str, fixture: &Path, name: &str) -> bool {
+    let expected: Option<Expected> = std::fs::read_to_string(fixture.with_extension("json"))
+        .ok()
+        .and_then(|e| serde_json::from_str(&e).ok());
+    let chat_id = expected
+        .as_ref()
+        .map(|e| e.chat_id.clone())
+        .unwrap_or_else(|| "0".to_string());
+
+    if let Err(e) = client
+        .goto(&format!("http://{addr}/{name}/t/{chat_id}/"))
+        .await
+    {
+        println!("  unable to load the page: {e:?}");
+        return false;
+    }
+
+    let chats = match client.get_chats().await {
+        Ok(chats) => chats
+            .iter()
+            .map(|c| ExpectedChat {
+                id: c.id.clone(),
+                unread: c.unread,
+            })
+            .collect::<Vec<_>>(),
+        Err(e) => {
+            println!("  unable to read chats: {e:?}");
+            Vec::new()
+        }
+    };
+    let messages = match client.get_messages(false).await {
+        Ok(messages) => messages,
+        Err(e) => {
+            println!("  unable to read messages: {e:?}");
+            Vec::new()
+        }
+    };
+    let actual = Expected {
+        chat_id,
+        chats: Some(chats),
+        messages: Some(messages),
+    };
+
+    let Some(expected) = expected else {
+        println!(
+            "  no valid {name}.json, this is what was read:\n{}",
+            serde_json::to_string_pretty(&actual).unwrap()
+        );
+        return false;
+    };
+
+    let mut ok = true;
+    if let Some(chats) = &expected.chats {
+        if Some(chats) != actual.chats.as_ref() {
+            println!(
+                "  chats differ\n  expected: {chats:?}\n  actual:   {:?}",
+                actual.chats
+            );
+            ok = false;
+        }
+    }
+    if let Some(messages) = &expected.messages {
+        // Compared as JSON, so fields that don't count for equality like reactions are checked too
+        let json = |m: &Vec<ChatMessage>| serde_json::to_value(m).unwrap();
+        if Some(json(messages)) != actual.messages.as_ref().map(json) {
+            println!(
+                "  messages differ\n  expected: {messages:?}\n  actual:   {:?}",
+                actual.messages
+            );
+            ok = false;
+        }
+    }
+    ok
+}
+
+/// Answers a single HTTP request with the fixture named in the first path segment
+async fn serve_page(mut stream: TcpStream, root: &Path) {
+    let mut buf = [0; 4096];
+    let Ok(x) = stream.read(&mut buf).await else {
+        return;
+    };
+    let request = String::from_utf8_lossy(&buf[..x]);
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+    debug!("Fixture request for {path}");
+
+    let name = path.split('/').find(|s| !s.is_empty()).unwrap_or_default();
+    let file = root.join(format!("{name}.html"));
+    let response = match (name.contains(".."), std::fs::read_to_string(&file)) {
+        (false, Ok(html)) => {
+            let html = strip_scripts(&html);
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{html}",
+                html.len()
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+/// Removes <script> elements so the recorded page stays exactly as it was captured
+fn strip_scripts(html: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    let mut res = String::with_capacity(html.len());
+    let mut pos = 0;
+    while let Some(start) = lower[pos..].find("<script").map(|s| s + pos) {
+        res.push_str(&html[pos..start]);
+        pos = match lower[start..].find("</script>") {
+            Some(end) => start + end + "</script>".len(),
+            None => html.len(),
+        };
+    }
+    res.push_str(&html[pos..]);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use serde_json::Value;
+
+    use super::*;
+    use crate::selectors::Selectors;
+
+    /// The locators each scraped message field is read with.
+    /// Every field has to be listed, so a new one can't be scraped without a fixture covering it.
+    const FIELD_LOCATORS: &[(&str, &[&str])] = &[
+        ("sender", &["sender_avatar"]),
+        ("content", &["message_body", "emoji", "unsent_marker"]),
+        // From the URL
+        ("chat_id", &[]),
+        ("id", &["message_id"]),
+        ("timestamp", &["message_time"]),
+        ("from_bot", &["own_message"]),
+        ("reply_to", &["reply_quote"]),
+        (
+            "attachments",
+            &[
+                "attachment_sticker",
+                "attachment_gif",
+                "attachment_image",
+                "attachment_video",
+                "attachment_audio",
+                "attachment_file",
+            ],
+        ),
+        ("reactions", &["reaction"]),
+        ("edited", &["edited_marker"]),
+        ("unsent", &["unsent_marker"]),
+    ];
+
+    /// Fields no fixture sets yet, since their locators haven't been checked against a recorded page.
+    /// Their built-in locators have to stay empty, and a field comes off this list once a fixture covers it.
+    const UNCOVERED: &[&str] = &[
+        "id",
+        "timestamp",
+        "from_bot",
+        "reply_to",
+        "attachments",
+        "reactions",
+        "edited",
+        "unsent",
+    ];
+
+    /// Whether a field has something other than its default
+    fn is_set(value: &Value) -> bool {
+        match value {
+            Value::Null | Value::Bool(false) => false,
+            Value::String(s) => !s.is_empty(),
+            Value::Array(a) => !a.is_empty(),
+            _ => true,
+        }
+    }
+
+    #[test]
+    fn every_field_has_a_fixture() {
+        let message: ChatMessage = serde_json::from_value(serde_json::json!({
+            "sender": "", "content": "", "chat_id": ""
+        }))
+        .unwrap();
+        let Value::Object(fields) = serde_json::to_value(message).unwrap() else {
+            unreachable!()
+        };
+        let listed: HashSet<&str> = FIELD_LOCATORS.iter().map(|(f, _)| *f).collect();
+        for field in fields.keys() {
+            assert!(
+                listed.contains(field.as_str()),
+                "`{field}` isn't in FIELD_LOCATORS"
+            );
+        }
+
+        // Every field a fixture has set
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+        let mut covered = HashSet::new();
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|e| e != "json") {
+                continue;
+            }
+            let expected: Expected =
+                serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            for message in expected.messages.unwrap_or_default() {
+                let Value::Object(message) = serde_json::to_value(message).unwrap() else {
+                    unreachable!()
+                };
+                covered.extend(
+                    message
+                        .into_iter()
+                        .filter(|(_, v)| is_set(v))
+                        .map(|(k, _)| k),
+                );
+            }
+        }
+
+        let selectors = serde_json::to_value(Selectors::default()).unwrap();
+        for (field, locators) in FIELD_LOCATORS {
+            if !UNCOVERED.contains(field) {
+                assert!(
+                    covered.contains(*field),
+                    "no fixture in {dir} has `{field}` set"
+                );
+                continue;
+            }
+            assert!(
+                !covered.contains(*field),
+                "`{field}` has a fixture now, take it out of UNCOVERED"
+            );
+            for l in *locators {
+                assert!(
+                    !is_set(&selectors[l]),
+                    "`{l}` is turned on, but no fixture in {dir} has `{field}` set"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+// Jackson Coxson
+// Framing for the TCP child protocol.
+// TCP is a stream, so a read can hold half a message, several messages,
+// or end in the middle of a UTF-8 character. Bytes are buffered here until
+// a whole frame is available.
+
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+/// How JSON documents are delimited on the socket
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Framing {
+    /// One JSON document per line
+    #[default]
+    Ndjson,
+    /// JSON documents back to back with no delimiter, like older holly.py clients send
+    Concatenated,
+}
+
+/// Why a frame couldn't be decoded
+#[derive(Debug)]
+pub enum FrameError {
+    TooLarge(usize),
+    InvalidUtf8,
+    InvalidJson(String),
+}
+
+/// Buffers reads from a client until whole frames are available
+pub struct FrameDecoder {
+    framing: Framing,
+    max_frame_size: usize,
+    buf: Vec<u8>,
+    /// Set while throwing away an oversized line until the next newline
+    discarding: bool,
+}
+
+impl FrameDecoder {
+    pub fn new(framing: Framing, max_frame_size: usize) -> Self {
+        Self {
+            framing,
+            max_frame_size,
+            buf: Vec::new(),
+            discarding: false,
+        }
+    }
+
+    /// Adds freshly read bytes and returns every frame that is now complete
+    pub fn push(&mut self, data: &[u8]) -> Vec<Result<String, FrameError>> {
+        self.buf.extend_from_slice(data);
+        match self.framing {
+            Framing::Ndjson => self.drain_lines(),
+            Framing::Concatenated => self.drain_documents(),
+        }
+    }
+
+    fn drain_lines(&mut self) -> Vec<Result<String, FrameError>> {
+        let mut res = Vec::new();
+        while let Some(pos) = self.buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=pos).collect();
+            if self.discarding {
+                // This is the tail end of a frame we already reported
+                self.discarding = false;
+                continue;
+            }
+            if line.len() - 1 > self.max_frame_size {
+                res.push(Err(FrameError::TooLarge(self.max_frame_size)));
+                continue;
+            }
+            match String::from_utf8(line) {
+                Ok(line) => {
+                    let line = line.trim();
+                    if !line.is_empty() {
+                        res.push(Ok(line.to_string()));
+                    }
+                }
+                Err(_) => res.push(Err(FrameError::InvalidUtf8)),
+            }
+        }
+
+        if self.buf.len() > self.max_frame_size {
+            self.buf.clear();
+            if !self.discarding {
+                self.discarding = true;
+                res.push(Err(FrameError::TooLarge(self.max_frame_size)));
+            }
+        }
+        res
+    }
+
+    fn drain_documents(&mut self) -> Vec<Result<String, FrameError>> {
+        let mut res = Vec::new();
+        let mut consumed = 0;
+        let mut stream =
+            serde_json::Deserializer::from_slice(&self.buf).into_iter::<serde::de::IgnoredAny>();
+        loop {
+            match stream.next() {
+                Some(Ok(_)) => {
+                    let end = stream.byte_offset();
+                    let doc = &self.buf[consumed..end];
+                    consumed = end;
+                    if doc.len() > self.max_frame_size {
+                        res.push(Err(FrameError::TooLarge(self.max_frame_size)));
+                        continue;
+                    }
+                    // serde_json already validated the UTF-8
+                    res.push(Ok(String::from_utf8_lossy(doc).trim().to_string()));
+                }
+                Some(Err(e)) if e.is_eof() => break,
+                Some(Err(e)) => {
+                    // There's no telling where the next document starts, so drop it all
+                    res.push(Err(FrameError::InvalidJson(e.to_string())));
+                    consumed = self.buf.len();
+                    break;
+                }
+                None => break,
+            }
+        }
+        self.buf.drain(..consumed);
+
+        if self.buf.len() > self.max_frame_size {
+            self.buf.clear();
+            res.push(Err(FrameError::TooLarge(self.max_frame_size)));
+        }
+        res
+    }
+}
+
+/// Wraps a serialized JSON document for sending to a client
+pub fn encode(framing: Framing, frame: &str) -> Vec<u8> {
+    let mut res = frame.as_bytes().to_vec();
+    if framing == Framing::Ndjson {
+        res.push(b'\n');
+    }
+    res
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::TooLarge(max) => write!(f, "frame exceeds the maximum size of {max} bytes"),
+            FrameError::InvalidUtf8 => write!(f, "frame is not valid UTF-8"),
+            FrameError::InvalidJson(e) => write!(f, "frame is not valid JSON: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(res: Vec<Result<String, FrameError>>) -> Vec<String> {
+        res.into_iter().map(|r| r.unwrap()).collect()
+    }
+
+    #[test]
+    fn partial_reads() {
+        for framing in [Framing::Ndjson, Framing::Concatenated] {
+            let mut d = FrameDecoder::new(framing, 1024);
+            assert!(d.push(b"{\"type\":").is_empty());
+            assert!(d.push(b"\"hello\"").is_empty());
+            let end: &[u8] = if framing == Framing::Ndjson {
+                b"}\n"
+            } else {
+                b"}"
+            };
+            assert_eq!(frames(d.push(end)), vec!["{\"type\":\"hello\"}"]);
+        }
+    }
+
+    #[test]
+    fn several_frames_in_one_read() {
+        let mut d = FrameDecoder::new(Framing::Ndjson, 1024);
+        assert_eq!(
+            frames(d.push(b"{\"a\":1}\n\n{\"b\":2}\n{\"c\"")),
+            vec!["{\"a\":1}", "{\"b\":2}"]
+        );
+        assert_eq!(frames(d.push(b":3}\n")), vec!["{\"c\":3}"]);
+
+        let mut d = FrameDecoder::new(Framing::Concatenated, 1024);
+        assert_eq!(
+            frames(d.push(b"{\"a\":1}{\"b\":2} {\"c\"")),
+            vec!["{\"a\":1}", "{\"b\":2}"]
+        );
+        assert_eq!(frames(d.push(b":3}")), vec!["{\"c\":3}"]);
+    }
+
+    #[test]
+    fn utf8_split_across_reads() {
+        let frame = "{\"content\":\"héllo 👋\"}";
+        let bytes = frame.as_bytes();
+        // Split inside the four byte wave
+        let split = frame.find('👋').unwrap() + 2;
+        for framing in [Framing::Ndjson, Framing::Concatenated] {
+            let mut d = FrameDecoder::new(framing, 1024);
+            assert!(d.push(&bytes[..split]).is_empty());
+            let mut rest = bytes[split..].to_vec();
+            if framing == Framing::Ndjson {
+                rest.push(b'\n');
+            }
+            assert_eq!(frames(d.push(&rest)), vec![frame]);
+        }
+    }
+
+    #[test]
+    fn braces_inside_strings() {
+        let frame = "{\"content\":\"}{ not a boundary }\\n{\"}";
+        for framing in [Framing::Ndjson, Framing::Concatenated] {
+            let mut d = FrameDecoder::new(framing, 1024);
+            let data = String::from_utf8(encode(framing, frame)).unwrap();
+            assert_eq!(frames(d.push(data.as_bytes())), vec![frame]);
+        }
+    }
+
+    #[test]
+    fn oversized_frames() {
+        let big = format!("{{\"content\":\"{}\"}}", "x".repeat(64));
+
+        let mut d = FrameDecoder::new(Framing::Ndjson, 32);
+        let res = d.push(format!("{big}\n{{\"a\":1}}\n").as_bytes());
+        assert!(matches!(res[0], Err(FrameError::TooLarge(32))));
+        assert_eq!(res[1].as_ref().unwrap(), "{\"a\":1}");
+
+        let mut d = FrameDecoder::new(Framing::Concatenated, 32);
+        let res = d.push(format!("{big}{{\"a\":1}}").as_bytes());
+        assert!(matches!(res[0], Err(FrameError::TooLarge(32))));
+        assert_eq!(res[1].as_ref().unwrap(), "{\"a\":1}");
+    }
+
+    #[test]
+    fn recovers_after_discarding() {
+        // The oversized line arrives over several reads and is reported once
+        let mut d = FrameDecoder::new(Framing::Ndjson, 32);
+        let res = d.push(format!("{{\"content\":\"{}", "x".repeat(40)).as_bytes());
+        assert!(matches!(res[..], [Err(FrameError::TooLarge(32))]));
+        assert!(d.push("x".repeat(40).as_bytes()).is_empty());
+        assert_eq!(frames(d.push(b"\"}\n{\"a\":1}\n")), vec!["{\"a\":1}"]);
+
+        // Without delimiters the tail can't be told apart from garbage,
+        // but the next document after it still gets through
+        let mut d = FrameDecoder::new(Framing::Concatenated, 32);
+        let res = d.push(format!("{{\"content\":\"{}", "x".repeat(40)).as_bytes());
+        assert!(matches!(res[..], [Err(FrameError::TooLarge(32))]));
+        let res = d.push(b"xxx\"}");
+        assert!(matches!(res[..], [Err(FrameError::InvalidJson(_))]));
+        assert_eq!(frames(d.push(b"{\"a\":1}")), vec!["{\"a\":1}"]);
+    }
+
+    #[test]
+    fn invalid_input() {
+        let mut d = FrameDecoder::new(Framing::Ndjson, 1024);
+        let res = d.push(b"\xff\xfe\n{\"a\":1}\n");
+        assert!(matches!(res[0], Err(FrameError::InvalidUtf8)));
+        assert_eq!(res[1].as_ref().unwrap(), "{\"a\":1}");
+
+        let mut d = FrameDecoder::new(Framing::Concatenated, 1024);
+        let res = d.push(b"{\"a\":}");
+        assert!(matches!(res[..], [Err(FrameError::InvalidJson(_))]));
+        assert_eq!(frames(d.push(b"{\"a\":1}")), vec!["{\"a\":1}"]);
+    }
+}
@@ -1,132 +1,232 @@
 // Jackson Coxson
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Instant};
 
-use chat::ChatMessage;
+use clap::Parser;
 use log::{debug, error, info, warn};
-use thirtyfour::error::WebDriverResult;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    sync::Mutex,
+use thirtyfour::error::{WebDriverError, WebDriverResult};
+use tokio::sync::{
+    mpsc::{Receiver, Sender},
+    Mutex,
 };
 
-use crate::cache::Cache;
+use crate::{
+    backend::ChatBackend,
+    browser::{Browser, Challenge, LoginState, SendError},
+    cache::{Cache, Revision},
+    chat::{MessageEdited, MessageRef, MessageUnsent},
+    cli::{Cli, Cmd},
+    config::{Backend, Config, Source},
+    mock::MockBackend,
+    protocol::{Command, Event, Priority},
+    queue::Outbox,
+    selectors::Selectors,
+    server::{Clients, Request},
+    typing::TypingOverride,
+};
 
+mod backend;
 mod browser;
 mod cache;
 mod chat;
+mod cli;
 mod config;
+mod fixture;
+mod framing;
+mod media;
+mod mock;
+mod protocol;
+mod queue;
+mod secrets;
+mod selectors;
+mod server;
+mod totp;
+mod typing;
+
+/// Seconds to wait for a login to go somewhere before giving up
+const LOGIN_TIMEOUT: u64 = 60;
+
+async fn entry(
+    source: &Source,
+    clear_cookies: bool,
+    clients: &Clients,
+    rx: &mut Receiver<Request>,
+    outbox: &mut Outbox,
+) -> WebDriverResult<()> {
+    let mut config = Config::load(source).map_err(WebDriverError::CustomError)?;
+    outbox.configure(&config.queue);
+    match config.backend {
+        Backend::Browser => {
+            let Some(client) =
+                start_browser(&mut config, clear_cookies, clients, rx, outbox).await?
+            else {
+                return Ok(());
+            };
+            run(&client, &mut config, clients, rx, outbox).await
+        }
+        Backend::Mock => {
+            info!("Running against the mock backend at {}", config.mock.script);
+            let client = MockBackend::load(&config.mock.script)?;
+            run(&client, &mut config, clients, rx, outbox).await
+        }
+    }
+}
 
-async fn entry(clear_cookies: bool) -> WebDriverResult<()> {
-    let config = config::Config::load();
-    let client = browser::Browser::new(&config).await.unwrap();
+/// Launches the browser and logs into Messenger.
+/// Returns `None` if a child asked for a restart while logging in.
+async fn start_browser(
+    config: &mut Config,
+    clear_cookies: bool,
+    clients: &Clients,
+    rx: &mut Receiver<Request>,
+    outbox: &mut Outbox,
+) -> WebDriverResult<Option<Browser>> {
+    let client = Browser::new(config).await?;
 
-    if !clear_cookies {
-        client.load_cookies().await.unwrap();
+    // A persistent profile keeps the session by itself
+    let restore = config.gecko.profile.is_none();
+    if !clear_cookies && restore {
+        if let Err(e) = client.load_cookies().await {
+            warn!("Unable to load cookies: {:?}", e);
+        }
+        if let Err(e) = client.load_storage().await {
+            warn!("Unable to load storage: {:?}", e);
+        }
     }
 
-    if !client.is_logged_in().await
-        && client
+    if !client.is_logged_in().await {
+        if client
             .login(&config.fb_username, &config.fb_password)
             .await
             .is_err()
-    {
-        warn!("Cookies are invalid, logging in again");
-        client.delete_cookies().await.unwrap();
-        client
-            .login(&config.fb_username, &config.fb_password)
-            .await
-            .unwrap();
-        client.dump_cookies().await.unwrap();
+        {
+            warn!("Cookies are invalid, logging in again");
+            client.delete_cookies().await?;
+            client
+                .login(&config.fb_username, &config.fb_password)
+                .await?;
+        }
+        if !finish_login(&client, config, clients, rx, outbox).await? {
+            return Ok(None);
+        }
     }
-    client.dump_cookies().await.unwrap();
-    if let Some(pin) = config.e2ee_pin {
-        client.enter_e2ee_pin(pin).await;
+    client.dump_cookies().await?;
+    if let Some(pin) = &config.e2ee_pin {
+        client.enter_e2ee_pin(pin.clone()).await;
     }
+    if restore {
+        if let Err(e) = client.dump_storage().await {
+            warn!("Unable to save storage: {:?}", e);
+        }
+    }
+    Ok(Some(client))
+}
 
-    let listener =
-        tokio::net::TcpListener::bind(format!("{}:{}", config.tcp.host, config.tcp.port))
-            .await
-            .unwrap();
+/// Waits for Messenger to load after logging in, getting past two-factor prompts with the TOTP secret
+/// and clicking through checkpoints that only want a "Continue".
+/// Anything else is reported to children with a screenshot, and they can answer with a `login_code`.
+/// Returns false if a child asked for a restart instead.
+async fn finish_login(
+    client: &Browser,
+    config: &mut Config,
+    clients: &Clients,
+    rx: &mut Receiver<Request>,
+    outbox: &mut Outbox,
+) -> WebDriverResult<bool> {
+    let started = Instant::now();
+    let mut totp_tried = false;
+    let mut continues = 0;
+    let mut announced: Option<(Challenge, Instant)> = None;
+    let mut error_count: u8 = 0;
+    loop {
+        match client.login_state().await? {
+            LoginState::LoggedIn => {
+                info!("Logged in");
+                return Ok(true);
+            }
+            LoginState::Pending => {
+                if announced.is_none() && started.elapsed().as_secs() > LOGIN_TIMEOUT {
+                    client.screenshot_log().await?;
+                    return Err(WebDriverError::CustomError(
+                        "Login didn't finish, check the credentials".to_string(),
+                    ));
+                }
+            }
+            LoginState::Challenge(Challenge::TwoFactor)
+                if config.totp_secret.is_some() && !totp_tried =>
+            {
+                totp_tried = true;
+                info!("Entering the two-factor code from the TOTP secret");
+                let code = totp::now(config.totp_secret.as_ref().unwrap())
+                    .map_err(WebDriverError::CustomError)?;
+                client.submit_login_code(&code).await?;
+            }
+            LoginState::Challenge(Challenge::Checkpoint)
+                if continues < 3 && client.continue_checkpoint().await? =>
+            {
+                info!("Continuing past a login checkpoint");
+                continues += 1;
+            }
+            LoginState::Challenge(challenge) => {
+                // Tell children when it changes, and every so often for any that connect later
+                if announced.is_none_or(|(c, t)| c != challenge || t.elapsed().as_secs() > 60) {
+                    warn!("Login needs a person: {challenge:?}");
+                    announced = Some((challenge, Instant::now()));
+                    if let Err(e) = client.screenshot_log().await {
+                        error!("Unable to save a screenshot: {:?}", e);
+                    }
+                    server::broadcast(
+                        clients,
+                        Event::LoginChallenge {
+                            challenge,
+                            url: client.current_url().await?,
+                            screenshot: client.screenshot_base64().await?,
+                        },
+                    );
+                }
+            }
+        }
 
-    let senders = Arc::new(Mutex::new(Vec::new()));
-    let tcp_senders = senders.clone();
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<ChatMessage>(100);
-
-    tokio::spawn(async move {
-        loop {
-            if let Ok((mut stream, addr)) = listener.accept().await {
-                info!("Accepted connection from {:?}", addr);
-
-                let (local_tx, mut local_rx) = tokio::sync::mpsc::channel::<ChatMessage>(100);
-                let tx = tx.clone();
-                tcp_senders.lock().await.push(local_tx);
-
-                tokio::spawn(async move {
-                    loop {
-                        let mut buf = [0; 4096];
-                        tokio::select! {
-                            msg = local_rx.recv() => {
-                                let msg = serde_json::to_string(&msg).unwrap();
-                                if stream.write(msg.as_bytes()).await.is_err() {
-                                    break;
-                                }
-                                if stream.flush().await.is_err() {
-                                    warn!("Unable to flush message to client");
-                                    break;
-                                }
-                            }
-                            x = stream.read(&mut buf) => {
-                                if let Ok(x) = x {
-                                    if let Ok(buf) = String::from_utf8(buf[0..x].to_vec()) {
-                                        if x == 0 {
-                                            break;
-                                        }
-                                        // Split the buf into JSON packets
-                                        // As we've learned, sometimes nagle's algo will squish them
-                                        // together into one packet, so we need to split them up
-                                        let packets = buf.split("}{")
-                                            .map(|s| {
-                                                let s = if s.ends_with('}') {
-                                                    s.to_string()
-                                                } else {
-                                                    format!("{s}}}")
-                                                };
-                                                if s.starts_with('{') {
-                                                    s.to_string()
-                                                } else {
-                                                    format!("{{{s}")
-                                                }
-                                            })
-                                            .collect::<Vec<_>>();
-
-                                        for packet in packets {
-                                            if let Ok(mut msg) = serde_json::from_str::<ChatMessage>(&packet) {
-                                                msg.clean();
-                                                tx.send(msg).await.unwrap();
-                                            } else {
-                                                warn!("Failed to parse msg: {:?}", buf);
-                                            }
-                                        }
-                                    }
-                                } else {
-                                    break;
-                                }
-                            }
-                        }
+        while let Ok(request) = rx.try_recv() {
+            if let Command::LoginCode { code } = &request.command {
+                match client.submit_login_code(code).await {
+                    Ok(()) => {
+                        info!("Submitted a login code");
+                        request.sent();
+                    }
+                    Err(e) => {
+                        error!("Unable to submit the login code: {:?}", e);
+                        request.failed(&SendError::WebDriver(e));
                     }
-                });
+                }
+                continue;
+            }
+            if handle_request(client, config, clients, outbox, request, &mut error_count).await {
+                return Ok(false);
             }
         }
-    });
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+}
 
+/// Polls for new messages and carries out commands until something goes badly wrong
+async fn run<B: ChatBackend>(
+    client: &B,
+    config: &mut Config,
+    clients: &Clients,
+    rx: &mut Receiver<Request>,
+    outbox: &mut Outbox,
+) -> WebDriverResult<()> {
     let mut cache = Cache::new();
-    let current_chat = client.get_current_chat().await.unwrap();
+    let current_chat = client.get_current_chat().await?;
     cache
-        .check(&current_chat, &client.get_messages(false).await.unwrap())
+        .check(&current_chat, &client.get_messages(false).await?)
         .await;
 
     let mut error_count: u8 = 0;
+    let mut last_health_check: Option<Instant> = None;
+    // Elements that failed the last selector check
+    let mut failing: Vec<String> = Vec::new();
 
     info!("Startup complete");
     loop {
@@ -140,6 +240,14 @@ async fn entry(clear_cookies: bool) -> WebDriverResult<()> {
             error!("Unable to decline call: {:?}", e);
         }
 
+        if config.health.interval > 0
+            && last_health_check
+                .is_none_or(|t| t.elapsed().as_millis() as u64 >= config.health.interval)
+        {
+            last_health_check = Some(Instant::now());
+            check_health(client, clients, &mut failing).await;
+        }
+
         // See if the current chat has different messages than before
         let current_message = match client.get_messages(false).await {
             Ok(c) => c,
@@ -188,92 +296,79 @@ async fn entry(clear_cookies: bool) -> WebDriverResult<()> {
         };
 
         if let Some(unread_messages) = cache.check(&current_chat, &current_message).await {
-            for message in unread_messages {
+            for mut message in unread_messages {
+                media::capture(client, &config.media, &mut message.attachments).await;
+                message
+                    .timestamp
+                    .get_or_insert_with(|| chrono::Utc::now().timestamp_millis());
                 info!(
                     "{} in {}: {}",
                     message.sender, current_chat, message.content
                 );
-                let blocking_senders = senders.clone();
-                tokio::task::spawn_blocking(move || {
-                    blocking_senders
-                        .blocking_lock()
-                        .retain(|sender| sender.blocking_send(message.clone()).is_ok());
-                });
+                server::broadcast(clients, Event::Message(message));
             }
         }
-
-        // Possibly send a message
-        if let Ok(msg) = rx.try_recv() {
-            match msg.sender.as_str() {
-                "<screenshot>" => {
-                    if let Err(e) = client.screenshot_log().await {
-                        error!("Unable to take screenshot!");
-                        error_count += 1;
-                        if error_count > 10 {
-                            return Err(e);
-                        }
-                    }
-                    continue;
+        for revision in cache.take_revisions() {
+            let event = match revision {
+                Revision::Edited { before, content } => {
+                    info!(
+                        "{} edited {:?} to {:?} in {}",
+                        before.sender, before.content, content, current_chat
+                    );
+                    Event::MessageEdited(MessageEdited {
+                        chat_id: before.chat_id.clone(),
+                        message: MessageRef::to(&before),
+                        content,
+                    })
                 }
-                "<html>" => {
-                    if let Err(e) = client.html_log().await {
-                        error!("Unable to take html log!");
-                        error_count += 1;
-                        if error_count > 10 {
-                            return Err(e);
-                        }
-                    }
-                    continue;
-                }
-                "<restart>" => return Ok(()),
-                "<refresh>" => {
-                    client.refresh().await?;
-                    continue;
-                }
-                "<file>" => {
-                    info!("Sending file!");
-                    if let Err(e) = client.go_to_chat(&msg.chat_id).await {
-                        error!("Unable to go to chat for file send: {:?}", e);
-                        error_count += 1;
-                        if error_count > 10 {
-                            return Err(e);
-                        }
-                        continue;
-                    }
-                    if let Err(e) = client.send_file(&msg.content).await {
-                        error!("Unable to send file: {:?}", e);
-                        error_count += 1;
-                        if error_count > 10 {
-                            return Err(e);
-                        }
-                        continue;
-                    }
-                    continue;
-                }
-                _ => {
-                    info!("Sending message: {:?}", msg);
-                    if let Err(e) = client.go_to_chat(&msg.chat_id).await {
-                        error!("Unable to go to chat for send: {:?}", e);
-                        error_count += 1;
-                        if error_count > 10 {
-                            return Err(e);
-                        }
-                        continue;
-                    }
-                    tokio::time::sleep(std::time::Duration::from_millis(config.latency as u64))
-                        .await;
-                    if let Err(e) = client.send_message(&msg.content).await {
-                        error!("Unable to send message: {:?}", e);
-                        error_count += 1;
-                        if error_count > 10 {
-                            return Err(e);
-                        }
-                        continue;
-                    }
-                    continue;
+                Revision::Unsent(before) => {
+                    info!(
+                        "{} unsent {:?} in {}",
+                        before.sender, before.content, current_chat
+                    );
+                    Event::MessageUnsent(MessageUnsent {
+                        chat_id: before.chat_id.clone(),
+                        message: MessageRef::to(&before),
+                    })
                 }
+            };
+            server::broadcast(clients, event);
+        }
+        let (added, removed) = cache.reactions(&current_chat, &current_message);
+        for change in added {
+            info!(
+                "{} reacted {} to {}",
+                change.sender.as_deref().unwrap_or("Someone"),
+                change.emoji,
+                change.message
+            );
+            server::broadcast(clients, Event::ReactionAdded(change));
+        }
+        for change in removed {
+            info!(
+                "{} took {} off {}",
+                change.sender.as_deref().unwrap_or("Someone"),
+                change.emoji,
+                change.message
+            );
+            server::broadcast(clients, Event::ReactionRemoved(change));
+        }
+
+        // Possibly send a message
+        while let Ok(request) = rx.try_recv() {
+            if handle_request(client, config, clients, outbox, request, &mut error_count).await {
+                return Ok(());
             }
         }
+        if error_count > 10 {
+            return Err(WebDriverError::CustomError(
+                "Too many errors carrying out commands".to_string(),
+            ));
+        }
+
+        if send_queued(client, config, outbox, &mut error_count).await? {
+            continue;
+        }
 
         // Check for unread messages
         let mut chats = match client.get_chats().await {
@@ -290,7 +385,7 @@ async fn entry(clear_cookies: bool) -> WebDriverResult<()> {
         debug!("Unread chats: {chats:?}");
         chats.retain(|chat| chat.unread || (!cache.check_key(&chat.id) && cache.size() < 20));
         if !chats.is_empty() {
-            if chats[0].click(config.latency).await.is_err() {
+            if client.go_to_chat(&chats[0].id).await.is_err() {
                 if let Err(e) = client.refresh().await {
                     error!("Unable to refresh, aborting Holly!");
                     error_count += 1;
@@ -310,22 +405,446 @@ async fn entry(clear_cookies: bool) -> WebDriverResult<()> {
     }
 }
 
+/// Carries out the next batch of queued commands.
+/// Returns whether there was anything to do.
+async fn send_queued<B: ChatBackend>(
+    client: &B,
+    config: &Config,
+    outbox: &mut Outbox,
+    error_count: &mut u8,
+) -> WebDriverResult<bool> {
+    let batch = outbox.next_batch(config.queue.batch_size);
+    if batch.is_empty() {
+        return Ok(false);
+    }
+    // The chat the browser is known to be sitting in
+    let mut open_chat: Option<String> = None;
+    // Chats that hit a retryable error, so later messages can't overtake
+    let mut held_chats = Vec::new();
+    let mut fatal = None;
+    for job in batch {
+        let chat_id = job
+            .request
+            .command
+            .chat_id()
+            .unwrap_or_default()
+            .to_string();
+        if held_chats.contains(&chat_id) {
+            outbox.release(job);
+            continue;
+        }
+        let in_chat = open_chat.as_ref() == Some(&chat_id);
+        match deliver(client, config, &job.request.command, in_chat).await {
+            Ok(()) => {
+                open_chat = Some(chat_id);
+                job.request.sent();
+                outbox.complete(job);
+            }
+            Err(SendError::WebDriver(e)) => {
+                error!("Unable to carry out {:?}: {:?}", job.request.command, e);
+                open_chat = None;
+                held_chats.push(chat_id);
+                if let Some(job) = outbox.retry(job) {
+                    job.request.failed(&SendError::WebDriver(e));
+                    outbox.abandon(job);
+                } else {
+                    *error_count += 1;
+                    fatal = Some(e);
+                }
+            }
+            Err(e) => {
+                error!("Unable to carry out {:?}: {}", job.request.command, e);
+                open_chat = None;
+                job.request.failed(&e);
+                outbox.abandon(job);
+            }
+        }
+    }
+    if let Some(e) = fatal {
+        if *error_count > 10 {
+            return Err(e);
+        }
+    }
+    Ok(true)
+}
+
+/// Checks the selectors against the page and tells the children how it went.
+/// Saves a screenshot and the HTML the first time an element stops matching.
+async fn check_health<B: ChatBackend>(client: &B, clients: &Clients, failing: &mut Vec<String>) {
+    let checks = match client.check_selectors().await {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Unable to check selectors: {:?}", e);
+            return;
+        }
+    };
+    if checks.is_empty() {
+        return;
+    }
+
+    let now_failing: Vec<String> = checks
+        .iter()
+        .filter(|c| !c.ok)
+        .map(|c| c.element.clone())
+        .collect();
+    let new_failures: Vec<&String> = now_failing
+        .iter()
+        .filter(|e| !failing.contains(e))
+        .collect();
+    if !new_failures.is_empty() {
+        warn!("Selectors stopped matching: {new_failures:?}");
+        if let Err(e) = client.screenshot_log().await {
+            error!("Unable to save a screenshot: {:?}", e);
+        }
+        if let Err(e) = client.html_log().await {
+            error!("Unable to save the page HTML: {:?}", e);
+        }
+    } else if now_failing.is_empty() && !failing.is_empty() {
+        info!("Selectors are matching again");
+    }
+    debug!("Selector health: {checks:?}");
+
+    server::broadcast(
+        clients,
+        Event::SelectorHealth {
+            healthy: now_failing.is_empty(),
+            checks,
+        },
+    );
+    *failing = now_failing;
+}
+
+/// Queues sends and carries out anything else a child asked for.
+/// Returns whether Holly should restart.
+async fn handle_request<B: ChatBackend>(
+    client: &B,
+    config: &mut Config,
+    clients: &Clients,
+    outbox: &mut Outbox,
+    request: Request,
+    error_count: &mut u8,
+) -> bool {
+    if let Err(e) = request.command.validate() {
+        request.failed(&SendError::Rejected(e));
+        return false;
+    }
+    match &request.command {
+        Command::SendMessage { .. } | Command::SendFile { .. } | Command::React { .. } => {
+            if outbox.has_completed(request.request_id.as_deref()) {
+                info!("Already sent request {:?}", request.request_id);
+                request.sent();
+            } else {
+                outbox.push(request);
+            }
+        }
+        Command::Restart => {
+            request.sent();
+            return true;
+        }
+        Command::ReloadConfig => match reload_config(client, config, clients, outbox) {
+            Ok(()) => request.sent(),
+            Err(e) => {
+                error!("Unable to reload the config: {}", e);
+                request.failed(&e);
+            }
+        },
+        Command::SetTyping { chat_id, typing } => match set_typing(config, chat_id, typing) {
+            Ok(()) => request.sent(),
+            Err(e) => request.failed(&e),
+        },
+        command => match control(client, config, command).await {
+            Ok(()) => request.sent(),
+            Err(e) => {
+                error!("Unable to carry out {:?}: {}", command, e);
+                request.failed(&e);
+                if let SendError::WebDriver(_) = e {
+                    *error_count += 1;
+                }
+            }
+        },
+    }
+    false
+}
+
+/// Rereads the config file and applies whatever can be applied while running.
+/// Nothing changes if the config or selectors file is invalid.
+/// Children are told which changed keys have to wait for a restart.
+fn reload_config<B: ChatBackend>(
+    client: &B,
+    config: &mut Config,
+    clients: &Clients,
+    outbox: &mut Outbox,
+) -> Result<(), SendError> {
+    let (new, changes) = config.reload().map_err(SendError::InvalidConfig)?;
+    // The selectors file is reread even if its path didn't change,
+    // and has to be valid too before anything is swapped in
+    let selectors = Selectors::load(&new.selectors).map_err(SendError::InvalidConfig)?;
+    *config = new;
+    client.set_selectors(selectors);
+    client.set_latency(config.latency);
+    outbox.configure(&config.queue);
+
+    info!("Reloaded the config, applied {:?}", changes.applied);
+    if !changes.needs_restart.is_empty() {
+        warn!(
+            "These config changes need the restart command: {:?}",
+            changes.needs_restart
+        );
+    }
+    if !changes.needs_relaunch.is_empty() {
+        warn!(
+            "These config changes need Holly to be started again: {:?}",
+            changes.needs_relaunch
+        );
+    }
+    server::broadcast(clients, Event::ConfigReloaded(changes));
+    Ok(())
+}
+
+/// Changes how messages to a chat are typed, or goes back to the config's settings
+fn set_typing(
+    config: &mut Config,
+    chat_id: &str,
+    typing: &Option<TypingOverride>,
+) -> Result<(), SendError> {
+    match typing {
+        Some(typing) => {
+            typing.validate().map_err(SendError::Rejected)?;
+            info!("Typing in {} with {:?}", chat_id, typing);
+            config
+                .typing
+                .chats
+                .insert(chat_id.to_string(), typing.clone());
+        }
+        None => {
+            info!("Typing in {} with the config's settings", chat_id);
+            config.typing.chats.remove(chat_id);
+        }
+    }
+    Ok(())
+}
+
+/// Carries out a command that doesn't go through the outbound queue
+async fn control<B: ChatBackend>(
+    client: &B,
+    config: &Config,
+    command: &Command,
+) -> Result<(), SendError> {
+    match command {
+        Command::Screenshot => client.screenshot_log().await?,
+        Command::Html => client.html_log().await?,
+        Command::Refresh => client.refresh().await?,
+        Command::ReloadSelectors => {
+            let selectors = Selectors::load(&config.selectors).map_err(SendError::InvalidConfig)?;
+            info!("Reloaded selectors version {}", selectors.version);
+            client.set_selectors(selectors);
+        }
+        Command::LoginCode { .. } => {
+            return Err(SendError::Rejected(
+                "not waiting for a login code".to_string(),
+            ))
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Sends a queued message or file.
+/// Navigation is skipped if the browser is already in the right chat.
+async fn deliver<B: ChatBackend>(
+    client: &B,
+    config: &Config,
+    command: &Command,
+    in_chat: bool,
+) -> Result<(), SendError> {
+    match command {
+        Command::SendFile { chat_id, path } => {
+            info!("Sending file to {}: {:?}", chat_id, path);
+            if !in_chat {
+                client.go_to_chat(chat_id).await?;
+            }
+            client.send_file(path).await
+        }
+        Command::React {
+            chat_id,
+            message,
+            emoji,
+            remove,
+        } => {
+            info!("Reacting {} to {} in {}", emoji, message, chat_id);
+            if !in_chat {
+                client.go_to_chat(chat_id).await?;
+                tokio::time::sleep(std::time::Duration::from_millis(config.latency as u64)).await;
+            }
+            client.react(message, emoji, *remove).await
+        }
+        Command::SendMessage {
+            chat_id,
+            content,
+            typing,
+            split,
+            reply_to,
+        } => {
+            info!("Sending message to {}: {:?}", chat_id, content);
+            if !in_chat {
+                client.go_to_chat(chat_id).await?;
+                tokio::time::sleep(std::time::Duration::from_millis(config.latency as u64)).await;
+            }
+            if let Some(target) = reply_to {
+                // Split messages only quote it on the first line
+                match client.start_reply(target).await {
+                    Err(SendError::Unsupported(e)) => {
+                        warn!("Unable to reply to {target}, sending without quoting it: {e}")
+                    }
+                    res => res?,
+                }
+            }
+            let style = config.typing.style(chat_id, typing.as_ref());
+            let content = style.prepare(content);
+            if !split {
+                return client.send_message(&content, &style).await;
+            }
+            // Blank lines would be empty messages, so they're dropped
+            let parts = content.lines().filter(|l| !l.trim().is_empty());
+            for (i, part) in parts.enumerate() {
+                if i > 0 {
+                    tokio::time::sleep(std::time::Duration::from_millis(config.latency as u64))
+                        .await;
+                }
+                client.send_message(part, &style).await?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
 #[tokio::main]
 async fn main() {
-    println!("Starting Holly core...");
-
-    if std::env::var("RUST_LOG").is_err() {
-        println!("Don't forget to initialize the logger with the RUST_LOG env var!!");
+    let cli = Cli::parse();
+    let source = cli.source();
+    let command = cli.command.unwrap_or(Cmd::Run);
+    if matches!(command, Cmd::Run | Cmd::LoginOnly) {
+        println!("Starting Holly core...");
+        if std::env::var("RUST_LOG").is_err() {
+            println!("Don't forget to initialize the logger with the RUST_LOG env var!!");
+        }
     }
 
     env_logger::init();
     info!("Logger initialized");
 
+    if let Cmd::Init { defaults, force } = command {
+        exit(Config::init(&source.path, defaults, force));
+    }
+    if let Cmd::MigrateConfig = command {
+        exit(Config::migrate_file(&source.path));
+    }
+    // Fixture runs only read local pages, so they don't log in
+    let config = match command {
+        Cmd::Fixtures { .. } => Config::load_offline(&source),
+        _ => Config::load(&source),
+    };
+    let config = match config {
+        Ok(c) => c,
+        Err(e) => exit(Err(e)),
+    };
+    match command {
+        Cmd::Run => start(&source, config).await,
+        Cmd::CheckConfig => exit(check_config(&config)),
+        Cmd::LoginOnly => exit(login_only(config).await),
+        Cmd::Fixtures { dir } => {
+            let passed = fixture::run(&config, &dir).await;
+            std::process::exit(if passed { 0 } else { 1 });
+        }
+        Cmd::Screenshot => exit(cli::remote(&config.tcp, Command::Screenshot).await),
+        Cmd::Html => exit(cli::remote(&config.tcp, Command::Html).await),
+        Cmd::Send {
+            chat,
+            split,
+            reply_to,
+            text,
+        } => exit(
+            cli::remote(
+                &config.tcp,
+                Command::SendMessage {
+                    chat_id: chat,
+                    content: text.join(" "),
+                    typing: None,
+                    split,
+                    reply_to: reply_to.map(|id| MessageRef {
+                        id: Some(id),
+                        sender: None,
+                        content: None,
+                    }),
+                },
+            )
+            .await,
+        ),
+        Cmd::React {
+            chat,
+            message,
+            remove,
+            emoji,
+        } => exit(
+            cli::remote(
+                &config.tcp,
+                Command::React {
+                    chat_id: chat,
+                    message: MessageRef {
+                        id: Some(message),
+                        sender: None,
+                        content: None,
+                    },
+                    emoji,
+                    remove,
+                },
+            )
+            .await,
+        ),
+        Cmd::LoginCode { code } => {
+            exit(cli::remote(&config.tcp, Command::LoginCode { code }).await)
+        }
+        Cmd::ReloadConfig => exit(cli::remote(&config.tcp, Command::ReloadConfig).await),
+        Cmd::Init { .. } | Cmd::MigrateConfig => unreachable!(),
+    }
+}
+
+/// Exits with an error message and status if anything went wrong
+fn exit(res: Result<(), String>) -> ! {
+    match res {
+        Ok(()) => std::process::exit(0),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Runs Holly until it's killed
+async fn start(source: &Source, config: Config) {
+    // Children and queued messages outlive restarts of the browser
+    let listener =
+        tokio::net::TcpListener::bind(format!("{}:{}", config.tcp.host, config.tcp.port))
+            .await
+            .unwrap();
+    let clients: Clients = Arc::new(Mutex::new(Vec::new()));
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Request>(100);
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(tx.clone()));
+    tokio::spawn(server::serve(
+        listener,
+        config.tcp.clone(),
+        clients.clone(),
+        tx,
+    ));
+    let mut outbox = Outbox::open(&config.queue);
+
     let mut last_error = std::time::Instant::now();
     let mut clear_cookies = false;
 
     loop {
-        if let Err(e) = entry(clear_cookies).await {
+        if let Err(e) = entry(source, clear_cookies, &clients, &mut rx, &mut outbox).await {
             error!("Holly crashed with {:?}", e);
             if last_error.elapsed().as_secs() > 60 {
                 tokio::time::sleep(std::time::Duration::from_secs(30)).await;
@@ -343,3 +862,388 @@ async fn main() {
         }
     }
 }
+
+/// Turns SIGHUP into a `reload_config` command, like most daemons
+#[cfg(unix)]
+async fn reload_on_hangup(tx: Sender<Request>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).unwrap();
+    while hangup.recv().await.is_some() {
+        info!("Got SIGHUP, reloading the config");
+        let request = Request {
+            command: Command::ReloadConfig,
+            request_id: None,
+            priority: Priority::Normal,
+            reply: None,
+        };
+        if tx.send(request).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Loads everything the config points at without starting a browser
+fn check_config(config: &Config) -> Result<(), String> {
+    let selectors = Selectors::load(&config.selectors)?;
+    let cipher = secrets::Cipher::from_config(&config.secrets)?;
+    match config.backend {
+        Backend::Browser => {
+            println!(
+                "Browser: {:?} through the WebDriver at {}",
+                config.browser,
+                config.gecko.url()
+            );
+        }
+        Backend::Mock => {
+            if !std::path::Path::new(&config.mock.script).exists() {
+                return Err(format!("Mock script {} doesn't exist", config.mock.script));
+            }
+            println!("Backend: mock, playing {}", config.mock.script);
+        }
+    }
+    println!("Children: {}:{}", config.tcp.host, config.tcp.port);
+    println!("Selectors: version {}", selectors.version);
+    println!(
+        "Session files: {}",
+        if cipher.is_some() {
+            "encrypted"
+        } else {
+            "not encrypted"
+        }
+    );
+    for key in &config.unknown_keys {
+        println!("Unknown key `{key}`, it's ignored");
+    }
+    println!("Config OK");
+    Ok(())
+}
+
+/// Logs in and saves the session, so the next start doesn't have to.
+/// Children can connect while it waits, to answer a two-factor prompt.
+async fn login_only(mut config: Config) -> Result<(), String> {
+    if config.backend == Backend::Mock {
+        return Err("The mock backend has nothing to log into".to_string());
+    }
+    let listener =
+        tokio::net::TcpListener::bind(format!("{}:{}", config.tcp.host, config.tcp.port))
+            .await
+            .map_err(|e| format!("Unable to listen for children: {e}"))?;
+    let clients: Clients = Arc::new(Mutex::new(Vec::new()));
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Request>(100);
+    tokio::spawn(server::serve(
+        listener,
+        config.tcp.clone(),
+        clients.clone(),
+        tx,
+    ));
+    let mut outbox = Outbox::open(&config.queue);
+
+    match start_browser(&mut config, false, &clients, &mut rx, &mut outbox).await {
+        Ok(Some(client)) => {
+            client
+                .quit()
+                .await
+                .map_err(|e| format!("Unable to close the browser: {e:?}"))?;
+            println!("Logged in, the session is saved");
+            Ok(())
+        }
+        Ok(None) => Err("Asked to restart before the login finished".to_string()),
+        Err(e) => Err(format!("Unable to log in: {e:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Sent;
+
+    const SCRIPT: &str = r#"{
+        "chats": [
+            {"id": "100", "messages": [
+                {"sender": "A", "content": "1"},
+                {"sender": "A", "content": "2"},
+                {"sender": "B", "content": "3"},
+                {"sender": "A", "content": "4"},
+                {"sender": "A", "content": "5"}
+            ]},
+            {"id": "200"}
+        ],
+        "events": [
+            {"at": 1000, "chat_id": "100", "sender": "B", "content": "ping"},
+            {"at": 2000, "chat_id": "100", "react": {"message": "mid.100.5", "sender": "A", "emoji": "👍"}}
+        ]
+    }"#;
+
+    struct Harness {
+        client: MockBackend,
+        config: Config,
+        clients: Clients,
+        outbox: Outbox,
+        path: std::path::PathBuf,
+    }
+
+    impl Harness {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("holly-main-{}-{name}.jsonl", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let config = Config {
+                latency: 0,
+                queue: config::Queue {
+                    path: path.to_string_lossy().to_string(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            Self {
+                client: MockBackend::parse(SCRIPT).unwrap(),
+                outbox: Outbox::open(&config.queue),
+                config,
+                clients: Arc::new(Mutex::new(Vec::new())),
+                path,
+            }
+        }
+
+        /// Queues a command the way a child's request would be, and carries out the queue.
+        /// Returns what Holly answered.
+        async fn request(&mut self, request_id: &str, command: serde_json::Value) -> Event {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+            let request = Request {
+                command: serde_json::from_value(command).unwrap(),
+                request_id: Some(request_id.to_string()),
+                priority: Priority::Normal,
+                reply: Some(tx),
+            };
+            let mut error_count = 0;
+            let restart = handle_request(
+                &self.client,
+                &mut self.config,
+                &self.clients,
+                &mut self.outbox,
+                request,
+                &mut error_count,
+            )
+            .await;
+            assert!(!restart);
+            while send_queued(
+                &self.client,
+                &self.config,
+                &mut self.outbox,
+                &mut error_count,
+            )
+            .await
+            .unwrap()
+            {}
+            rx.try_recv().unwrap()
+        }
+    }
+
+    impl Drop for Harness {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    fn contents(messages: &[chat::ChatMessage]) -> Vec<&str> {
+        messages.iter().map(|m| m.content.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn polls_new_messages_and_reactions() {
+        let h = Harness::new("poll");
+        let mut cache = Cache::new();
+        let messages = h.client.get_messages(false).await.unwrap();
+        assert!(cache.check("100", &messages).await.is_none());
+        cache.reactions("100", &messages);
+
+        // Nothing changed, so there's nothing to report
+        let messages = h.client.get_messages(false).await.unwrap();
+        assert!(cache.check("100", &messages).await.is_none());
+
+        h.client.advance(1000);
+        let messages = h.client.get_messages(false).await.unwrap();
+        let new = cache.check("100", &messages).await.unwrap();
+        assert_eq!(contents(&new), vec!["ping"]);
+        assert_eq!(new[0].id.as_deref(), Some("mid.100.5"));
+        cache.reactions("100", &messages);
+
+        h.client.advance(1000);
+        let messages = h.client.get_messages(false).await.unwrap();
+        assert!(cache.check("100", &messages).await.is_none());
+        let (added, removed) = cache.reactions("100", &messages);
+        assert!(removed.is_empty());
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].emoji, "👍");
+        assert_eq!(added[0].message.id.as_deref(), Some("mid.100.5"));
+    }
+
+    #[tokio::test]
+    async fn dispatches_queued_sends_once() {
+        let mut h = Harness::new("dispatch");
+        let send = serde_json::json!({
+            "type": "send_message",
+            "chat_id": "200",
+            "content": "hi\n\nthere",
+            "split": true
+        });
+        let answer = h.request("1", send.clone()).await;
+        assert!(matches!(answer, Event::Sent { request_id } if request_id == "1"));
+        let sent = |content: &str| Sent::Message {
+            chat_id: "200".to_string(),
+            content: content.to_string(),
+            reply_to: None,
+        };
+        assert_eq!(h.client.sent(), vec![sent("hi"), sent("there")]);
+
+        // A repeat of the same request is answered without sending again
+        let answer = h.request("1", send).await;
+        assert!(matches!(answer, Event::Sent { .. }));
+        assert_eq!(h.client.sent().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn replies_and_reacts() {
+        let mut h = Harness::new("reply");
+        let answer = h
+            .request(
+                "1",
+                serde_json::json!({
+                    "type": "send_message",
+                    "chat_id": "100",
+                    "content": "pong",
+                    "reply_to": {"sender": "B", "content": "3"}
+                }),
+            )
+            .await;
+        assert!(matches!(answer, Event::Sent { .. }));
+        let answer = h
+            .request(
+                "2",
+                serde_json::json!({
+                    "type": "react",
+                    "chat_id": "100",
+                    "message": {"id": "mid.100.0"},
+                    "emoji": "❤️"
+                }),
+            )
+            .await;
+        assert!(matches!(answer, Event::Sent { .. }));
+        assert_eq!(
+            h.client.sent(),
+            vec![
+                Sent::Message {
+                    chat_id: "100".to_string(),
+                    content: "pong".to_string(),
+                    reply_to: Some("mid.100.2".to_string()),
+                },
+                Sent::Reaction {
+                    chat_id: "100".to_string(),
+                    message: "mid.100.0".to_string(),
+                    emoji: "❤️".to_string(),
+                    remove: false,
+                },
+            ]
+        );
+        let messages = h.client.get_messages(false).await.unwrap();
+        assert_eq!(messages[0].reactions[0].sender.as_deref(), Some("You"));
+    }
+
+    #[tokio::test]
+    async fn reload_applies_nothing_unless_everything_is_valid() {
+        let mut h = Harness::new("reload");
+        let path = h.path.with_extension("toml");
+        let selectors = h.path.with_extension("selectors.toml");
+        std::fs::write(
+            &path,
+            format!(
+                "fb_username = \"holly\"\nfb_password = \"pw\"\nrefresh_rate = 123\nselectors = {:?}\n",
+                selectors.to_string_lossy()
+            ),
+        )
+        .unwrap();
+        std::fs::write(&selectors, "textbox = ").unwrap();
+        h.config.source = config::Source {
+            path: path.to_string_lossy().to_string(),
+            overrides: Vec::new(),
+        };
+        let (tx, mut events) = tokio::sync::mpsc::channel(10);
+        h.clients.lock().await.push(tx);
+        let reload = serde_json::json!({"type": "reload_config"});
+
+        let answer = h.request("1", reload.clone()).await;
+        assert!(
+            matches!(&answer, Event::Failed { reason, .. } if reason.contains("selectors")),
+            "{answer:?}"
+        );
+        assert_ne!(h.config.refresh_rate, 123);
+        let wait = std::time::Duration::from_millis(200);
+        assert!(tokio::time::timeout(wait, events.recv()).await.is_err());
+
+        std::fs::write(&selectors, "textbox = [\"//div\"]").unwrap();
+        let answer = h.request("2", reload).await;
+        assert!(matches!(answer, Event::Sent { .. }), "{answer:?}");
+        assert_eq!(h.config.refresh_rate, 123);
+        assert!(matches!(
+            tokio::time::timeout(wait, events.recv()).await,
+            Ok(Some(Event::ConfigReloaded(_)))
+        ));
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(selectors).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replies_without_a_reply_button_are_sent_plain() {
+        let mut h = Harness::new("plain-reply");
+        h.client =
+            MockBackend::parse(&SCRIPT.replacen('{', r#"{"unsupported": ["reply"],"#, 1)).unwrap();
+        let answer = h
+            .request(
+                "1",
+                serde_json::json!({
+                    "type": "send_message",
+                    "chat_id": "100",
+                    "content": "pong",
+                    "reply_to": {"id": "mid.100.2"}
+                }),
+            )
+            .await;
+        assert!(matches!(answer, Event::Sent { .. }));
+        assert_eq!(
+            h.client.sent(),
+            vec![Sent::Message {
+                chat_id: "100".to_string(),
+                content: "pong".to_string(),
+                reply_to: None,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_requests_fail_again_on_retry() {
+        let mut h = Harness::new("failed");
+        let send = serde_json::json!({
+            "type": "send_message",
+            "chat_id": "999",
+            "content": "hi"
+        });
+        for _ in 0..2 {
+            let answer = h.request("1", send.clone()).await;
+            assert!(matches!(answer, Event::Failed { error, .. } if error == "chat_not_found"));
+        }
+        let answer = h
+            .request(
+                "2",
+                serde_json::json!({
+                    "type": "send_message",
+                    "chat_id": "100",
+                    "content": "hi",
+                    "reply_to": {"id": "mid.404"}
+                }),
+            )
+            .await;
+        assert!(matches!(answer, Event::Failed { error, .. } if error == "message_not_found"));
+        assert!(h.client.sent().is_empty());
+    }
+}
@@ -0,0 +1,136 @@
+// Jackson Coxson
+// Attachments on inbound messages and the folder they're saved to.
+// Scraping only finds the kind and URL of an attachment. New messages have
+// their attachments downloaded through the backend, so the browser's session
+// is used, and stored under a name taken from a hash of the contents.
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{backend::ChatBackend, config};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttachmentKind {
+    Image,
+    Gif,
+    Sticker,
+    Video,
+    Audio,
+    File,
+}
+
+/// Something attached to a message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+    #[serde(rename = "type")]
+    pub kind: AttachmentKind,
+    /// Where it was saved, if it was downloaded
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub mime: Option<String>,
+    /// Size in bytes, if it was downloaded
+    #[serde(default)]
+    pub size: Option<u64>,
+    /// Where Messenger serves it from
+    pub url: String,
+}
+
+/// Downloads each attachment that hasn't been saved yet.
+/// Attachments that fail to download are still passed on with just their URL.
+pub async fn capture<B: ChatBackend>(
+    client: &B,
+    config: &config::Media,
+    attachments: &mut [Attachment],
+) {
+    if !config.download {
+        return;
+    }
+    for attachment in attachments.iter_mut().filter(|a| a.path.is_none()) {
+        match client.fetch_media(&attachment.url, config.max_size).await {
+            Ok((bytes, mime)) => match save(config, &bytes, &mime, &attachment.url) {
+                Ok(path) => {
+                    info!("Saved {:?} attachment to {}", attachment.kind, path);
+                    attachment.size = Some(bytes.len() as u64);
+                    attachment.mime = Some(mime).filter(|m| !m.is_empty());
+                    attachment.path = Some(path);
+                }
+                Err(e) => warn!("Unable to save attachment {}: {}", attachment.url, e),
+            },
+            Err(e) => warn!("Unable to download attachment {}: {}", attachment.url, e),
+        }
+    }
+}
+
+/// Writes the bytes to the media folder, returning the path
+fn save(config: &config::Media, bytes: &[u8], mime: &str, url: &str) -> Result<String, String> {
+    std::fs::create_dir_all(&config.dir)
+        .map_err(|e| format!("Unable to create {}: {e}", config.dir))?;
+    let digest = ring::digest::digest(&ring::digest::SHA256, bytes);
+    let name: String = digest.as_ref()[..16]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    let path = std::path::Path::new(&config.dir).join(format!("{name}.{}", extension(mime, url)));
+    if !path.exists() {
+        std::fs::write(&path, bytes).map_err(|e| format!("Unable to write {path:?}: {e}"))?;
+    }
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// A file extension for the MIME type, or failing that the one in the URL
+fn extension(mime: &str, url: &str) -> String {
+    let known = match mime.split(';').next().unwrap_or_default().trim() {
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        "audio/mpeg" => "mp3",
+        "audio/mp4" | "audio/x-m4a" => "m4a",
+        "audio/ogg" => "ogg",
+        "audio/wav" | "audio/x-wav" => "wav",
+        "application/pdf" => "pdf",
+        _ => "",
+    };
+    if !known.is_empty() {
+        return known.to_string();
+    }
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    match path
+        .rsplit_once('/')
+        .map_or(path, |(_, f)| f)
+        .rsplit_once('.')
+    {
+        Some((_, ext))
+            if !ext.is_empty()
+                && ext.len() <= 5
+                && ext.chars().all(|c| c.is_ascii_alphanumeric()) =>
+        {
+            ext.to_ascii_lowercase()
+        }
+        _ => "bin".to_string(),
+    }
+}
+
+/// Guesses the MIME type of a local file from its extension
+pub fn mime_for(path: &str) -> String {
+    let ext = extension("", path);
+    match ext.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+    .to_string()
+}
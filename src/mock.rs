@@ -0,0 +1,467 @@
+// Jackson Coxson
+// In-memory chat backend driven by a JSON script.
+// Lets Holly run without a browser, so children and the main loop can be
+// exercised offline. Messages in the script arrive at their `at` offset in
+// milliseconds after startup, and anything Holly sends is logged and recorded
+// so tests can check it.
+
+use std::{sync::Mutex, time::Instant};
+
+use log::info;
+use serde::Deserialize;
+use thirtyfour::error::{WebDriverError, WebDriverResult};
+
+use crate::{
+    backend::ChatBackend,
+    browser::SendError,
+    chat::{self, ChatMessage, ChatPreview, MessageRef, Reaction, ReplyTarget},
+    media::{self, Attachment},
+    typing::Style,
+};
+
+#[derive(Deserialize)]
+struct Script {
+    #[serde(default)]
+    chats: Vec<ScriptChat>,
+    #[serde(default)]
+    events: Vec<ScriptEvent>,
+    /// Actions that fail with `unsupported`, like they do in the browser when their locators are off
+    #[serde(default)]
+    unsupported: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ScriptChat {
+    id: String,
+    #[serde(default)]
+    messages: Vec<ScriptMessage>,
+}
+
+#[derive(Deserialize)]
+struct ScriptMessage {
+    sender: String,
+    content: String,
+    #[serde(default)]
+    from_bot: bool,
+    /// The id of the message this one replies to.
+    /// Messages get ids like `mid.<chat id>.<index>`.
+    #[serde(default)]
+    reply_to: Option<String>,
+    /// Attachments, with `url` naming a local file to "download"
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+}
+
+#[derive(Deserialize)]
+struct ScriptEvent {
+    at: u64,
+    chat_id: String,
+    #[serde(flatten)]
+    action: ScriptAction,
+}
+
+/// What a scripted event does: a new message, a reaction under `react`,
+/// or a change to a message under `edit` or `unsend`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScriptAction {
+    React { react: ScriptReaction },
+    Edit { edit: ScriptEdit },
+    Unsend { unsend: ScriptUnsend },
+    Message(ScriptMessage),
+}
+
+#[derive(Deserialize)]
+struct ScriptEdit {
+    /// The id of the message edited
+    message: String,
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct ScriptUnsend {
+    /// The id of the message unsent
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct ScriptReaction {
+    /// The id of the message reacted to
+    message: String,
+    sender: String,
+    emoji: String,
+    #[serde(default)]
+    remove: bool,
+}
+
+struct MockChat {
+    id: String,
+    unread: bool,
+    messages: Vec<ChatMessage>,
+}
+
+/// Something Holly did to a mock chat
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sent {
+    Message {
+        chat_id: String,
+        content: String,
+        /// The id of the message it replied to
+        reply_to: Option<String>,
+    },
+    File {
+        chat_id: String,
+        path: String,
+    },
+    Reaction {
+        chat_id: String,
+        /// The id of the message reacted to
+        message: String,
+        emoji: String,
+        remove: bool,
+    },
+}
+
+struct State {
+    chats: Vec<MockChat>,
+    /// Scripted messages that haven't arrived yet, soonest last
+    pending: Vec<ScriptEvent>,
+    current: String,
+    /// The message the next send replies to
+    replying: Option<String>,
+    sent: Vec<Sent>,
+    /// Milliseconds `advance` has skipped ahead
+    skipped: u64,
+    unsupported: Vec<String>,
+}
+
+pub struct MockBackend {
+    started: Instant,
+    state: Mutex<State>,
+}
+
+impl MockBackend {
+    /// Loads a script from a JSON file
+    pub fn load(path: &str) -> WebDriverResult<Self> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            WebDriverError::CustomError(format!("Unable to read mock script {path}: {e}"))
+        })?;
+        Self::parse(&contents)
+            .map_err(|e| WebDriverError::CustomError(format!("Invalid mock script {path}: {e}")))
+    }
+
+    /// Builds a backend from a script's JSON
+    pub fn parse(script: &str) -> serde_json::Result<Self> {
+        Ok(Self::new(serde_json::from_str(script)?))
+    }
+
+    fn new(script: Script) -> Self {
+        let chats: Vec<MockChat> = script
+            .chats
+            .into_iter()
+            .map(|c| {
+                let mut chat = MockChat {
+                    id: c.id,
+                    unread: false,
+                    messages: Vec::new(),
+                };
+                for m in c.messages {
+                    chat.push(m, None);
+                }
+                chat
+            })
+            .collect();
+        let mut pending = script.events;
+        pending.sort_by_key(|e| std::cmp::Reverse(e.at));
+
+        Self {
+            started: Instant::now(),
+            state: Mutex::new(State {
+                current: chats.first().map(|c| c.id.clone()).unwrap_or_default(),
+                chats,
+                pending,
+                replying: None,
+                sent: Vec::new(),
+                skipped: 0,
+                unsupported: script.unsupported,
+            }),
+        }
+    }
+
+    /// Everything sent so far, oldest first
+    #[cfg(test)]
+    pub fn sent(&self) -> Vec<Sent> {
+        self.state.lock().unwrap().sent.clone()
+    }
+
+    /// Moves the script's clock forward, so events arrive without waiting for them
+    #[cfg(test)]
+    pub fn advance(&self, ms: u64) {
+        self.state.lock().unwrap().skipped += ms;
+    }
+
+    /// Delivers every scripted message that is due
+    fn tick(&self, state: &mut State) {
+        let elapsed = self.started.elapsed().as_millis() as u64 + state.skipped;
+        while state.pending.last().is_some_and(|e| e.at <= elapsed) {
+            let event = state.pending.pop().unwrap();
+            let current = state.current.clone();
+            let chat = match state.chats.iter_mut().find(|c| c.id == event.chat_id) {
+                Some(c) => c,
+                None => {
+                    state.chats.push(MockChat {
+                        id: event.chat_id.clone(),
+                        unread: false,
+                        messages: Vec::new(),
+                    });
+                    state.chats.last_mut().unwrap()
+                }
+            };
+            match event.action {
+                ScriptAction::Message(message) => {
+                    chat.unread = chat.id != current;
+                    chat.push(message, Some(chrono::Utc::now().timestamp_millis()));
+                }
+                ScriptAction::React { react } => chat.react(
+                    &react.message,
+                    Reaction {
+                        emoji: chat::normalize_emoji(&react.emoji),
+                        sender: Some(react.sender),
+                    },
+                    react.remove,
+                ),
+                ScriptAction::Edit { edit } => chat.revise(&edit.message, |m| {
+                    m.content = edit.content;
+                    m.edited = true;
+                }),
+                ScriptAction::Unsend { unsend } => chat.revise(&unsend.message, |m| {
+                    // Like Messenger, only a placeholder is left
+                    m.content = format!("{} unsent a message", m.sender);
+                    m.unsent = true;
+                    m.attachments.clear();
+                    m.reactions.clear();
+                }),
+            }
+        }
+    }
+}
+
+impl State {
+    fn check_supported(&self, action: &str) -> Result<(), SendError> {
+        match self.unsupported.iter().any(|a| a == action) {
+            true => Err(SendError::Unsupported(format!(
+                "The mock script turns off {action}"
+            ))),
+            false => Ok(()),
+        }
+    }
+}
+
+impl MockChat {
+    /// Adds a scripted message, giving it an id and resolving what it replies to
+    fn push(&mut self, message: ScriptMessage, timestamp: Option<i64>) {
+        let reply_to = message.reply_to.map(|id| {
+            let target = self.messages.iter().find(|m| m.id.as_ref() == Some(&id));
+            ReplyTarget {
+                sender: target.map(|t| t.sender.clone()),
+                content: target.map(|t| t.content.clone()).unwrap_or_default(),
+                id: Some(id),
+            }
+        });
+        self.messages.push(ChatMessage {
+            sender: message.sender,
+            content: message.content,
+            chat_id: self.id.clone(),
+            id: Some(format!("mid.{}.{}", self.id, self.messages.len())),
+            timestamp,
+            from_bot: message.from_bot,
+            reply_to,
+            attachments: message.attachments,
+            reactions: Vec::new(),
+            edited: false,
+            unsent: false,
+        });
+    }
+
+    /// Changes a message that's already in the chat
+    fn revise(&mut self, id: &str, change: impl FnOnce(&mut ChatMessage)) {
+        match self
+            .messages
+            .iter_mut()
+            .find(|m| m.id.as_deref() == Some(id))
+        {
+            Some(message) => change(message),
+            None => info!("Mock change to unknown message {id}"),
+        }
+    }
+
+    /// Adds or removes a reaction. Like Messenger, everyone gets one reaction per message.
+    fn react(&mut self, id: &str, reaction: Reaction, remove: bool) {
+        let Some(message) = self
+            .messages
+            .iter_mut()
+            .find(|m| m.id.as_deref() == Some(id))
+        else {
+            info!("Mock reaction to unknown message {id}");
+            return;
+        };
+        message.reactions.retain(|r| r.sender != reaction.sender);
+        if !remove {
+            message.reactions.push(reaction);
+        }
+    }
+}
+
+impl ChatBackend for MockBackend {
+    async fn get_chats(&self) -> WebDriverResult<Vec<ChatPreview>> {
+        let mut state = self.state.lock().unwrap();
+        self.tick(&mut state);
+        Ok(state
+            .chats
+            .iter()
+            .map(|c| ChatPreview {
+                id: c.id.clone(),
+                unread: c.unread,
+            })
+            .collect())
+    }
+
+    async fn go_to_chat(&self, id: &str) -> Result<(), SendError> {
+        let mut state = self.state.lock().unwrap();
+        let chat = state
+            .chats
+            .iter_mut()
+            .find(|c| c.id == id)
+            .ok_or(SendError::ChatNotFound)?;
+        chat.unread = false;
+        state.current = id.to_string();
+        Ok(())
+    }
+
+    async fn get_messages(&self, last: bool) -> WebDriverResult<Vec<ChatMessage>> {
+        let mut state = self.state.lock().unwrap();
+        self.tick(&mut state);
+        let messages = state
+            .chats
+            .iter()
+            .find(|c| c.id == state.current)
+            .map(|c| c.messages.clone())
+            .unwrap_or_default();
+        if last {
+            return Ok(messages.last().cloned().into_iter().collect());
+        }
+        Ok(messages)
+    }
+
+    async fn start_reply(&self, target: &MessageRef) -> Result<(), SendError> {
+        let mut state = self.state.lock().unwrap();
+        state.check_supported("reply")?;
+        let message = state
+            .chats
+            .iter()
+            .find(|c| c.id == state.current)
+            .and_then(|c| c.messages.iter().rev().find(|m| target.matches(m)))
+            .ok_or_else(|| SendError::MessageNotFound(target.to_string()))?;
+        info!("Mock replying to {:?}", message);
+        state.replying = message.id.clone();
+        Ok(())
+    }
+
+    async fn react(&self, target: &MessageRef, emoji: &str, remove: bool) -> Result<(), SendError> {
+        let mut state = self.state.lock().unwrap();
+        state.check_supported("react")?;
+        let current = state.current.clone();
+        let chat = state
+            .chats
+            .iter_mut()
+            .find(|c| c.id == current)
+            .ok_or(SendError::ChatNotFound)?;
+        let id = chat
+            .messages
+            .iter()
+            .rev()
+            .find(|m| target.matches(m))
+            .and_then(|m| m.id.clone())
+            .ok_or_else(|| SendError::MessageNotFound(target.to_string()))?;
+        info!("Mock reacting {} to {}", emoji, id);
+        chat.react(
+            &id,
+            Reaction {
+                emoji: chat::normalize_emoji(emoji),
+                sender: Some(chat::OWN_SENDER.to_string()),
+            },
+            remove,
+        );
+        state.sent.push(Sent::Reaction {
+            chat_id: current,
+            message: id,
+            emoji: emoji.to_string(),
+            remove,
+        });
+        Ok(())
+    }
+
+    async fn send_message(&self, message: &str, style: &Style) -> Result<(), SendError> {
+        let mut state = self.state.lock().unwrap();
+        info!(
+            "Mock sent to {}: {:?} ({:?})",
+            state.current, message, style.mode
+        );
+        let sent = Sent::Message {
+            chat_id: state.current.clone(),
+            content: message.to_string(),
+            reply_to: state.replying.take(),
+        };
+        state.sent.push(sent);
+        Ok(())
+    }
+
+    async fn send_file(&self, path: &str) -> Result<(), SendError> {
+        if !std::path::Path::new(path).is_file() {
+            return Err(SendError::UploadFailed);
+        }
+        let mut state = self.state.lock().unwrap();
+        info!("Mock sent file to {}: {:?}", state.current, path);
+        let sent = Sent::File {
+            chat_id: state.current.clone(),
+            path: path.to_string(),
+        };
+        state.sent.push(sent);
+        Ok(())
+    }
+
+    async fn refresh(&self) -> WebDriverResult<()> {
+        Ok(())
+    }
+
+    async fn get_current_chat(&self) -> WebDriverResult<String> {
+        Ok(self.state.lock().unwrap().current.clone())
+    }
+
+    async fn decline_call(&self) -> WebDriverResult<()> {
+        Ok(())
+    }
+
+    async fn screenshot_log(&self) -> WebDriverResult<()> {
+        info!("Mock screenshot requested");
+        Ok(())
+    }
+
+    async fn html_log(&self) -> WebDriverResult<()> {
+        info!("Mock html log requested");
+        Ok(())
+    }
+
+    async fn fetch_media(&self, url: &str, max_size: u64) -> WebDriverResult<(Vec<u8>, String)> {
+        // Script attachment URLs are local files
+        let bytes = std::fs::read(url).map_err(|e| {
+            WebDriverError::CustomError(format!("Unable to read mock attachment {url}: {e}"))
+        })?;
+        if bytes.len() as u64 > max_size {
+            return Err(WebDriverError::CustomError(format!(
+                "{url} is larger than {max_size} bytes"
+            )));
+        }
+        Ok((bytes, media::mime_for(url)))
+    }
+}
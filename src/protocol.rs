@@ -0,0 +1,258 @@
+// Jackson Coxson
+// Messages exchanged with children over TCP.
+// Version 1 is the original protocol: bare `ChatMessage` JSON both ways, with
+// control commands hidden in the `sender` field. Version 2 wraps everything in
+// an envelope like `{"v":2,"type":"send_message",...}`. A child opts into v2
+// by sending a `hello` frame, and v1 frames are accepted on any connection.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    browser::Challenge,
+    chat::{ChatMessage, MessageEdited, MessageRef, MessageUnsent, ReactionChange},
+    config::Reload,
+    selectors::SelectorCheck,
+    typing::TypingOverride,
+};
+
+/// The newest protocol version Holly speaks
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// A versioned frame on the wire
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub v: u8,
+    /// Chosen by the child to match up `ack`, `sent` and `failed` events with its commands
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Where the command goes in the outbound queue
+    #[serde(default, skip_serializing_if = "Priority::is_normal")]
+    pub priority: Priority,
+    #[serde(flatten)]
+    pub body: T,
+}
+
+/// How soon a queued command should be carried out
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Commands sent from a child to Holly
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    /// Negotiates the protocol version from the envelope's `v`.
+    /// Handled by the connection, never queued.
+    Hello,
+    SendMessage {
+        chat_id: String,
+        content: String,
+        /// Changes how this message is typed, including whether it's transliterated to ASCII
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        typing: Option<TypingOverride>,
+        /// Sends each line as a message of its own instead of line breaks in one
+        #[serde(default)]
+        split: bool,
+        /// Replies to this message instead of just posting into the chat
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<MessageRef>,
+    },
+    SendFile {
+        chat_id: String,
+        path: String,
+    },
+    /// Adds a reaction to a message, or takes ours off with `remove`
+    React {
+        chat_id: String,
+        message: MessageRef,
+        emoji: String,
+        #[serde(default)]
+        remove: bool,
+    },
+    Screenshot,
+    Html,
+    Restart,
+    Refresh,
+    /// Rereads the selectors file without restarting the browser
+    ReloadSelectors,
+    /// A two-factor or checkpoint code for a login that's waiting on one
+    LoginCode {
+        code: String,
+    },
+    /// Rereads the config file, applying what can be applied without a restart
+    ReloadConfig,
+    /// Changes how messages to a chat are typed until the config is reloaded.
+    /// Leaving out `typing` goes back to the config's settings.
+    SetTyping {
+        chat_id: String,
+        #[serde(default)]
+        typing: Option<TypingOverride>,
+    },
+}
+
+/// Events sent from Holly to children
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// Reply to `hello` with the version the connection will use
+    Welcome { version: u8 },
+    /// A new message was read from a chat
+    Message(ChatMessage),
+    /// A command was accepted and queued
+    Ack { request_id: String },
+    /// A command was carried out
+    Sent { request_id: String },
+    /// A command couldn't be carried out. `error` is a stable code, `reason` is for humans.
+    Failed {
+        request_id: String,
+        error: String,
+        reason: String,
+    },
+    /// A frame from the child couldn't be handled
+    Error { reason: String },
+    /// Login is stuck on something a person has to deal with.
+    /// `screenshot` is a base64 PNG of the page.
+    LoginChallenge {
+        challenge: Challenge,
+        url: String,
+        screenshot: String,
+    },
+    /// Periodic check of the selectors against the live page
+    SelectorHealth {
+        healthy: bool,
+        checks: Vec<SelectorCheck>,
+    },
+    /// The config file was reloaded
+    ConfigReloaded(Reload),
+    /// Someone reacted to a message that was already on screen
+    ReactionAdded(ReactionChange),
+    /// A reaction was taken off a message
+    ReactionRemoved(ReactionChange),
+    /// A message that was already passed on was edited
+    MessageEdited(MessageEdited),
+    /// A message that was already passed on was unsent
+    MessageUnsent(MessageUnsent),
+}
+
+/// Parses a frame from a child in either the v1 or v2 shape.
+/// v1 frames come back in an envelope with `v` set to 1.
+pub fn parse_command(frame: &str) -> Result<Envelope<Command>, String> {
+    let value: Value = serde_json::from_str(frame).map_err(|e| e.to_string())?;
+    let envelope = if value.get("type").is_none() {
+        let msg: ChatMessage = serde_json::from_value(value).map_err(|e| e.to_string())?;
+        Envelope {
+            v: 1,
+            request_id: None,
+            priority: Priority::Normal,
+            body: Command::from_legacy(msg),
+        }
+    } else {
+        serde_json::from_value::<Envelope<Command>>(value).map_err(|e| e.to_string())?
+    };
+    if envelope.v > PROTOCOL_VERSION && !matches!(envelope.body, Command::Hello) {
+        return Err(format!("unsupported protocol version {}", envelope.v));
+    }
+    Ok(envelope)
+}
+
+impl Priority {
+    fn is_normal(&self) -> bool {
+        *self == Priority::Normal
+    }
+}
+
+impl Command {
+    /// The chat the command sends into, if any
+    pub fn chat_id(&self) -> Option<&str> {
+        match self {
+            Self::SendMessage { chat_id, .. }
+            | Self::SendFile { chat_id, .. }
+            | Self::React { chat_id, .. } => Some(chat_id),
+            _ => None,
+        }
+    }
+
+    /// Checks the fields a command can't be carried out without
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Self::SendMessage {
+                typing, reply_to, ..
+            } => {
+                if let Some(typing) = typing {
+                    typing.validate()?;
+                }
+                if let Some(reply_to) = reply_to {
+                    reply_to.validate()?;
+                }
+                Ok(())
+            }
+            Self::React { message, emoji, .. } => {
+                message.validate()?;
+                // The emoji ends up in the XPath that finds it in the picker
+                if emoji.trim().is_empty() || emoji.contains(['\'', '"']) {
+                    return Err(format!("{emoji:?} isn't an emoji"));
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Converts a v1 message, where the sender doubles as the command name
+    fn from_legacy(msg: ChatMessage) -> Self {
+        match msg.sender.as_str() {
+            "<screenshot>" => Self::Screenshot,
+            "<html>" => Self::Html,
+            "<restart>" => Self::Restart,
+            "<refresh>" => Self::Refresh,
+            "<file>" => Self::SendFile {
+                chat_id: msg.chat_id,
+                path: msg.content,
+            },
+            _ => Self::SendMessage {
+                chat_id: msg.chat_id,
+                content: msg.content,
+                typing: None,
+                split: false,
+                reply_to: None,
+            },
+        }
+    }
+}
+
+impl Event {
+    /// Serializes the event for a connection speaking the given version.
+    /// Returns `None` if the event has no v1 equivalent.
+    pub fn encode(&self, version: u8) -> Option<String> {
+        if version >= 2 {
+            let envelope = Envelope {
+                v: version,
+                request_id: None,
+                priority: Priority::Normal,
+                body: self,
+            };
+            return Some(serde_json::to_string(&envelope).unwrap());
+        }
+        match self {
+            Event::Message(msg) => Some(serde_json::to_string(msg).unwrap()),
+            Event::Error { reason } => Some(serde_json::json!({ "error": reason }).to_string()),
+            Event::Welcome { .. }
+            | Event::Ack { .. }
+            | Event::Sent { .. }
+            | Event::Failed { .. }
+            | Event::SelectorHealth { .. }
+            | Event::LoginChallenge { .. }
+            | Event::ConfigReloaded(_)
+            | Event::ReactionAdded(_)
+            | Event::ReactionRemoved(_)
+            | Event::MessageEdited(_)
+            | Event::MessageUnsent(_) => None,
+        }
+    }
+}
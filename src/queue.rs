@@ -0,0 +1,397 @@
+// Jackson Coxson
+// Durable queue for outbound messages and files.
+// Every change is appended to a JSONL journal, so anything still pending
+// when Holly restarts or crashes is picked up again on the next start.
+// The journal is compacted every time it's opened.
+
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader, Write},
+};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{config, secrets, server::Request};
+
+/// How many finished request ids to remember for dedupe
+const COMPLETED_MEMORY: usize = 1000;
+
+/// A queued request and its delivery state
+#[derive(Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub attempts: u32,
+    /// Unix timestamp in milliseconds before which the job shouldn't be tried
+    pub retry_at: i64,
+    pub request: Request,
+}
+
+/// A line in the journal. Generic so jobs can be written by reference.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Entry<J> {
+    Push(J),
+    Retry {
+        id: u64,
+        attempts: u32,
+        retry_at: i64,
+    },
+    /// The job is finished. `request_id` is only set if it was delivered.
+    Done {
+        id: u64,
+        request_id: Option<String>,
+    },
+    /// A delivered request id that's still remembered after compaction
+    Completed {
+        request_id: String,
+    },
+}
+
+pub struct Outbox {
+    path: String,
+    max_attempts: u32,
+    retry_delay: u64,
+    next_id: u64,
+    jobs: Vec<Job>,
+    completed: VecDeque<String>,
+}
+
+impl Outbox {
+    /// Opens the journal, replaying and compacting whatever is in it
+    pub fn open(config: &config::Queue) -> Self {
+        let mut res = Self {
+            path: config.path.clone(),
+            max_attempts: config.max_attempts,
+            retry_delay: config.retry_delay,
+            next_id: 0,
+            jobs: Vec::new(),
+            completed: VecDeque::new(),
+        };
+
+        if let Ok(file) = std::fs::File::open(&res.path) {
+            for line in BufReader::new(file).lines().map_while(Result::ok) {
+                match serde_json::from_str::<Entry<Job>>(&line) {
+                    Ok(entry) => res.replay(entry),
+                    Err(e) => warn!("Skipping bad queue journal entry: {e:?}"),
+                }
+            }
+        }
+        if !res.jobs.is_empty() {
+            info!("Recovered {} queued jobs", res.jobs.len());
+        }
+        res.compact();
+        res
+    }
+
+    /// Picks up new retry settings. The journal stays where it was opened.
+    pub fn configure(&mut self, config: &config::Queue) {
+        self.max_attempts = config.max_attempts;
+        self.retry_delay = config.retry_delay;
+    }
+
+    /// Whether a request with this id was already carried out
+    pub fn has_completed(&self, request_id: Option<&str>) -> bool {
+        request_id.is_some_and(|id| self.completed.iter().any(|c| c == id))
+    }
+
+    /// Adds a request to the queue, unless one with the same request id is already waiting
+    pub fn push(&mut self, request: Request) {
+        if let Some(request_id) = &request.request_id {
+            if let Some(job) = self
+                .jobs
+                .iter_mut()
+                .find(|j| j.request.request_id.as_ref() == Some(request_id))
+            {
+                // The child may have reconnected, so answer on the newest connection
+                job.request.reply = request.reply;
+                return;
+            }
+        }
+
+        let job = Job {
+            id: self.next_id,
+            attempts: 0,
+            retry_at: 0,
+            request,
+        };
+        self.next_id += 1;
+        self.write(&Entry::Push(&job));
+        self.jobs.push(job);
+    }
+
+    /// Takes up to `limit` of the most important jobs that are ready to be tried,
+    /// grouped by chat so each chat only has to be opened once.
+    /// Chats are ordered by their most important job, and each chat's jobs stay in the order
+    /// they were queued, so nothing is taken from a chat while an earlier job in it waits on a retry.
+    /// Every job has to be handed back with `complete`, `retry` or `release`.
+    pub fn next_batch(&mut self, limit: usize) -> Vec<Job> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let (mut ready, mut waiting): (Vec<Job>, Vec<Job>) =
+            self.jobs.drain(..).partition(|j| j.retry_at <= now);
+        // The oldest job each chat has waiting on a retry
+        let mut held: Vec<(Option<&str>, u64)> = Vec::new();
+        for job in &waiting {
+            let chat_id = job.request.command.chat_id();
+            match held.iter_mut().find(|(c, _)| *c == chat_id) {
+                Some((_, id)) => *id = (*id).min(job.id),
+                None => held.push((chat_id, job.id)),
+            }
+        }
+        let (free, behind): (Vec<Job>, Vec<Job>) = ready.drain(..).partition(|j| {
+            let chat_id = j.request.command.chat_id();
+            !held.iter().any(|(c, id)| *c == chat_id && *id < j.id)
+        });
+        ready = free;
+        waiting.extend(behind);
+        // Highest priority first, then oldest first
+        ready.sort_by(|a, b| {
+            b.request
+                .priority
+                .cmp(&a.request.priority)
+                .then(a.id.cmp(&b.id))
+        });
+        waiting.extend(ready.drain(limit.max(1).min(ready.len())..));
+        self.jobs = waiting;
+
+        let mut chats: Vec<Vec<Job>> = Vec::new();
+        for job in ready {
+            let chat_id = job.request.command.chat_id();
+            match chats
+                .iter_mut()
+                .find(|c| c[0].request.command.chat_id() == chat_id)
+            {
+                Some(chat) => chat.push(job),
+                None => chats.push(vec![job]),
+            }
+        }
+        chats
+            .into_iter()
+            .flat_map(|mut chat| {
+                chat.sort_by_key(|j| j.id);
+                chat
+            })
+            .collect()
+    }
+
+    /// Hands a job back untouched
+    pub fn release(&mut self, job: Job) {
+        self.jobs.push(job);
+    }
+
+    /// Marks a job as delivered, so repeats of its request id are answered without sending
+    pub fn complete(&mut self, job: Job) {
+        self.write(&Entry::<&Job>::Done {
+            id: job.id,
+            request_id: job.request.request_id.clone(),
+        });
+        if let Some(request_id) = job.request.request_id {
+            self.remember(request_id);
+        }
+    }
+
+    /// Drops a job that couldn't be delivered. Its request id can be tried again.
+    pub fn abandon(&mut self, job: Job) {
+        self.write(&Entry::<&Job>::Done {
+            id: job.id,
+            request_id: None,
+        });
+    }
+
+    /// Puts a failed job back with a backoff. Returns the job if it's out of attempts.
+    pub fn retry(&mut self, mut job: Job) -> Option<Job> {
+        job.attempts += 1;
+        if job.attempts >= self.max_attempts {
+            return Some(job);
+        }
+        let delay = self
+            .retry_delay
+            .saturating_mul(1 << (job.attempts - 1).min(16));
+        job.retry_at = chrono::Utc::now().timestamp_millis() + delay as i64;
+        warn!(
+            "Retrying job {} in {}ms (attempt {} of {})",
+            job.id, delay, job.attempts, self.max_attempts
+        );
+        self.write(&Entry::<&Job>::Retry {
+            id: job.id,
+            attempts: job.attempts,
+            retry_at: job.retry_at,
+        });
+        self.jobs.push(job);
+        None
+    }
+
+    /// Applies a journal entry to the in-memory state
+    fn replay(&mut self, entry: Entry<Job>) {
+        match entry {
+            Entry::Push(job) => {
+                self.next_id = self.next_id.max(job.id + 1);
+                self.jobs.push(job);
+            }
+            Entry::Retry {
+                id,
+                attempts,
+                retry_at,
+            } => {
+                if let Some(job) = self.jobs.iter_mut().find(|j| j.id == id) {
+                    job.attempts = attempts;
+                    job.retry_at = retry_at;
+                }
+            }
+            Entry::Done { id, request_id } => {
+                self.jobs.retain(|j| j.id != id);
+                if let Some(request_id) = request_id {
+                    self.remember(request_id);
+                }
+            }
+            Entry::Completed { request_id } => self.remember(request_id),
+        }
+    }
+
+    fn remember(&mut self, request_id: String) {
+        if self.completed.len() >= COMPLETED_MEMORY {
+            self.completed.pop_front();
+        }
+        self.completed.push_back(request_id);
+    }
+
+    fn write(&self, entry: &Entry<&Job>) {
+        let line = serde_json::to_string(entry).unwrap();
+        let res = secrets::private_options()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{line}"));
+        if let Err(e) = res {
+            error!("Unable to write to queue journal {}: {:?}", self.path, e);
+        }
+    }
+
+    /// Rewrites the journal with only what's still needed
+    fn compact(&self) {
+        let mut entries = Vec::new();
+        for request_id in &self.completed {
+            entries.push(Entry::Completed {
+                request_id: request_id.clone(),
+            });
+        }
+        entries.extend(self.jobs.iter().map(Entry::Push));
+
+        let mut contents = String::new();
+        for entry in entries {
+            contents.push_str(&serde_json::to_string(&entry).unwrap());
+            contents.push('\n');
+        }
+        if let Err(e) = secrets::write_private(&self.path, contents.as_bytes()) {
+            error!("Unable to compact queue journal {}: {:?}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Command, Priority};
+
+    fn outbox(name: &str) -> Outbox {
+        let path =
+            std::env::temp_dir().join(format!("holly-queue-{}-{name}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Outbox::open(&config::Queue {
+            path: path.to_string_lossy().to_string(),
+            ..Default::default()
+        })
+    }
+
+    fn request(request_id: &str) -> Request {
+        Request {
+            command: Command::SendMessage {
+                chat_id: "100".to_string(),
+                content: "hi".to_string(),
+                typing: None,
+                split: false,
+                reply_to: None,
+            },
+            request_id: Some(request_id.to_string()),
+            priority: Priority::Normal,
+            reply: None,
+        }
+    }
+
+    #[test]
+    fn only_delivered_ids_are_remembered() {
+        let mut outbox = outbox("delivered");
+        outbox.push(request("a"));
+        outbox.push(request("b"));
+        let mut batch = outbox.next_batch(10).into_iter();
+        outbox.complete(batch.next().unwrap());
+        outbox.abandon(batch.next().unwrap());
+        assert!(outbox.has_completed(Some("a")));
+        assert!(!outbox.has_completed(Some("b")));
+
+        // The same holds after the journal is replayed and compacted, twice
+        for _ in 0..2 {
+            outbox = Outbox::open(&config::Queue {
+                path: outbox.path.clone(),
+                ..Default::default()
+            });
+            assert!(outbox.has_completed(Some("a")));
+            assert!(!outbox.has_completed(Some("b")));
+            assert!(outbox.jobs.is_empty());
+        }
+        let _ = std::fs::remove_file(&outbox.path);
+    }
+
+    #[test]
+    fn retried_jobs_are_not_overtaken() {
+        let mut outbox = outbox("order");
+        outbox.push(request("a"));
+        outbox.push(request("b"));
+        let mut other = request("c");
+        if let Command::SendMessage { chat_id, .. } = &mut other.command {
+            *chat_id = "200".to_string();
+        }
+        outbox.push(other);
+
+        // The first message fails and waits, the second is held back behind it
+        let mut batch = outbox.next_batch(10).into_iter();
+        assert!(outbox.retry(batch.next().unwrap()).is_none());
+        for job in batch {
+            outbox.release(job);
+        }
+        let batch = outbox.next_batch(10);
+        let ids: Vec<_> = batch.iter().map(|j| j.id).collect();
+        assert_eq!(ids, [2]);
+        for job in batch {
+            outbox.complete(job);
+        }
+
+        // Once it's due, both go out in order
+        for job in &mut outbox.jobs {
+            job.retry_at = 0;
+        }
+        let ids: Vec<_> = outbox.next_batch(10).iter().map(|j| j.id).collect();
+        assert_eq!(ids, [0, 1]);
+        let _ = std::fs::remove_file(&outbox.path);
+    }
+
+    #[test]
+    fn pending_jobs_survive_a_restart() {
+        let mut outbox = outbox("pending");
+        outbox.push(request("a"));
+        outbox.push(request("a"));
+        outbox.push(request("b"));
+        let job = outbox.next_batch(1).pop().unwrap();
+        assert!(outbox.retry(job).is_none());
+
+        let outbox = Outbox::open(&config::Queue {
+            path: outbox.path.clone(),
+            ..Default::default()
+        });
+        assert_eq!(outbox.jobs.len(), 2);
+        let retried = outbox.jobs.iter().find(|j| j.id == 0).unwrap();
+        assert_eq!(retried.attempts, 1);
+        assert!(retried.retry_at > 0);
+        let _ = std::fs::remove_file(&outbox.path);
+    }
+}
@@ -0,0 +1,94 @@
+// Reads everything Holly needs from message rows in one round trip.
+// Arguments are the row elements, the locators by name, and the attributes
+// ids and times can be kept in. Locators are tried in order like they are in
+// Rust, and an empty list matches nothing. Returns the raw strings for each
+// row, which are parsed on the Rust side.
+var rows = arguments[0];
+var locators = arguments[1];
+var idAttributes = arguments[2];
+var timeAttributes = arguments[3];
+
+function all(root, name) {
+  var list = locators[name] || [];
+  for (var i = 0; i < list.length; i++) {
+    var found;
+    try {
+      found = document.evaluate(list[i], root, null, XPathResult.ORDERED_NODE_SNAPSHOT_TYPE, null);
+    } catch (e) {
+      continue;
+    }
+    if (found.snapshotLength > 0) {
+      var res = [];
+      for (var j = 0; j < found.snapshotLength; j++) res.push(found.snapshotItem(j));
+      return res;
+    }
+  }
+  return [];
+}
+
+function first(root, name) {
+  return all(root, name)[0] || null;
+}
+
+function text(element) {
+  return element ? element.innerText : null;
+}
+
+function attr(element, name) {
+  return element ? element.getAttribute(name) : null;
+}
+
+function attributes(element, names) {
+  var res = [];
+  if (!element) return res;
+  for (var i = 0; i < names.length; i++) {
+    var value = element.getAttribute(names[i]);
+    if (value) res.push(value);
+  }
+  return res;
+}
+
+// Videos and audio can keep their URL on a <source> inside instead
+function mediaUrl(element, kind) {
+  if (kind === 'file') return attr(element, 'href');
+  var src = attr(element, 'src');
+  if (src) return src;
+  var source = element.querySelector('source');
+  return source ? attr(source, 'src') : null;
+}
+
+var kinds = ['sticker', 'gif', 'image', 'video', 'audio', 'file'];
+
+return rows.map(function (row) {
+  var attachments = [];
+  kinds.forEach(function (kind) {
+    all(row, 'attachment_' + kind).forEach(function (element) {
+      attachments.push({ type: kind, url: mediaUrl(element, kind) });
+    });
+  });
+
+  var time = first(row, 'message_time');
+  var times = attributes(time, timeAttributes);
+  if (time) times.push(time.innerText);
+
+  return {
+    body: text(first(row, 'message_body')),
+    emoji: attr(first(row, 'emoji'), 'alt'),
+    placeholder: text(first(row, 'unsent_marker')),
+    own: first(row, 'own_message') !== null,
+    avatar: attr(first(row, 'sender_avatar'), 'alt'),
+    ids: attributes(first(row, 'message_id'), idAttributes),
+    times: times,
+    quote: text(first(row, 'reply_quote')),
+    attachments: attachments,
+    reactions: all(row, 'reaction').map(function (element) {
+      return {
+        label: attr(element, 'aria-label'),
+        alts: Array.prototype.map.call(element.querySelectorAll('img'), function (img) {
+          return img.getAttribute('alt');
+        })
+      };
+    }),
+    edited: first(row, 'edited_marker') !== null
+  };
+});
@@ -0,0 +1,266 @@
+// Jackson Coxson
+// Keeping credentials out of config.toml and session files unreadable.
+// Secrets can come from environment variables or files, which is how systemd
+// credentials and Docker secrets hand them over. Files Holly writes are only
+// readable by its own user, and the cookie jar can be encrypted with a key
+// derived from a passphrase or key file.
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use log::debug;
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey},
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::config::Secrets;
+
+/// Marks a file as encrypted by Holly, followed by the salt, nonce and ciphertext
+const MAGIC: &[u8] = b"HOLLYENC1";
+const SALT_LEN: usize = 16;
+const PBKDF2_ROUNDS: u32 = 600_000;
+
+/// Looks up a secret by name, in order:
+/// the `HOLLY_<NAME>` env var, a file named by `HOLLY_<NAME>_FILE`,
+/// `<name>` in the configured secrets folder, then `<name>` in systemd's `$CREDENTIALS_DIRECTORY`.
+pub fn lookup(name: &str, secrets: &Secrets) -> Option<String> {
+    let var = format!("HOLLY_{}", name.to_uppercase());
+    if let Ok(value) = std::env::var(&var) {
+        debug!("Using {name} from ${var}");
+        return Some(value);
+    }
+
+    let mut files: Vec<PathBuf> = Vec::new();
+    if let Ok(path) = std::env::var(format!("{var}_FILE")) {
+        files.push(path.into());
+    }
+    if let Some(dir) = &secrets.dir {
+        files.push(Path::new(dir).join(name));
+    }
+    if let Ok(dir) = std::env::var("CREDENTIALS_DIRECTORY") {
+        files.push(Path::new(&dir).join(name));
+    }
+    for file in files {
+        if let Ok(value) = std::fs::read_to_string(&file) {
+            debug!("Using {name} from {}", file.display());
+            return Some(value.trim_end_matches(['\r', '\n']).to_string());
+        }
+    }
+    None
+}
+
+/// Writes a file only Holly's user can read.
+/// Files that already exist are locked down too, since the mode only applies on creation.
+pub fn write_private(path: impl AsRef<Path>, contents: &[u8]) -> std::io::Result<()> {
+    let mut file = private_options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(contents)
+}
+
+/// Options for opening files that should only be readable by Holly's user
+pub fn private_options() -> OpenOptions {
+    #[allow(unused_mut)]
+    let mut options = OpenOptions::new();
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+}
+
+/// Encrypts session files at rest
+pub struct Cipher {
+    secret: Vec<u8>,
+    /// Keys already derived, by salt. Deriving takes a noticeable fraction of a second,
+    /// so every file this cipher writes reuses the first salt.
+    keys: Mutex<Vec<([u8; SALT_LEN], [u8; 32])>>,
+}
+
+impl Cipher {
+    /// Builds the cipher from the configured passphrase or key file, if there is one
+    pub fn from_config(secrets: &Secrets) -> Result<Option<Self>, String> {
+        if let Some(passphrase) = lookup("passphrase", secrets) {
+            return Ok(Some(Self::new(passphrase.into_bytes())));
+        }
+        match &secrets.key_file {
+            Some(path) => std::fs::read(path)
+                .map(|secret| Some(Self::new(secret)))
+                .map_err(|e| format!("Unable to read key file {path}: {e}")),
+            None => Ok(None),
+        }
+    }
+
+    fn new(secret: Vec<u8>) -> Self {
+        Self {
+            secret,
+            keys: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let rng = SystemRandom::new();
+        let mut nonce = [0; aead::NONCE_LEN];
+        rng.fill(&mut nonce).unwrap();
+        let salt = match self.keys.lock().unwrap().first() {
+            Some((salt, _)) => *salt,
+            None => {
+                let mut salt = [0; SALT_LEN];
+                rng.fill(&mut salt).unwrap();
+                salt
+            }
+        };
+
+        let mut data = plaintext.to_vec();
+        self.key(&salt)
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(MAGIC),
+                &mut data,
+            )
+            .unwrap();
+
+        let mut res = MAGIC.to_vec();
+        res.extend_from_slice(&salt);
+        res.extend_from_slice(&nonce);
+        res.extend_from_slice(&data);
+        res
+    }
+
+    pub fn decrypt(&self, contents: &[u8]) -> Result<Vec<u8>, String> {
+        let header = MAGIC.len() + SALT_LEN + aead::NONCE_LEN;
+        if !is_encrypted(contents) || contents.len() < header {
+            return Err("not an encrypted file".to_string());
+        }
+        let salt = &contents[MAGIC.len()..MAGIC.len() + SALT_LEN];
+        let nonce = Nonce::try_assume_unique_for_key(&contents[MAGIC.len() + SALT_LEN..header])
+            .map_err(|_| "invalid nonce".to_string())?;
+        let mut data = contents[header..].to_vec();
+        let plaintext = self
+            .key(salt)
+            .open_in_place(nonce, Aad::from(MAGIC), &mut data)
+            .map_err(|_| "wrong passphrase or key file, or the file is damaged".to_string())?;
+        Ok(plaintext.to_vec())
+    }
+
+    fn key(&self, salt: &[u8]) -> LessSafeKey {
+        let mut keys = self.keys.lock().unwrap();
+        let key = match keys.iter().find(|(s, _)| s == salt) {
+            Some((_, key)) => *key,
+            None => {
+                let mut key = [0; 32];
+                pbkdf2::derive(
+                    pbkdf2::PBKDF2_HMAC_SHA256,
+                    NonZeroU32::new(PBKDF2_ROUNDS).unwrap(),
+                    salt,
+                    &self.secret,
+                    &mut key,
+                );
+                keys.push((salt.try_into().unwrap(), key));
+                key
+            }
+        };
+        LessSafeKey::new(UnboundKey::new(&aead::AES_256_GCM, &key).unwrap())
+    }
+}
+
+pub fn is_encrypted(contents: &[u8]) -> bool {
+    contents.starts_with(MAGIC)
+}
+
+/// Writes a session file, encrypted if there's a cipher
+pub fn save(path: &str, contents: &[u8], cipher: Option<&Cipher>) -> std::io::Result<()> {
+    match cipher {
+        Some(c) => write_private(path, &c.encrypt(contents)),
+        None => write_private(path, contents),
+    }
+}
+
+/// Reads a session file written by `save`. Plain files are read as they are,
+/// so existing ones keep working until they're next saved.
+pub fn load(path: &str, cipher: Option<&Cipher>) -> Result<Option<Vec<u8>>, String> {
+    let Ok(contents) = std::fs::read(path) else {
+        return Ok(None);
+    };
+    if !is_encrypted(&contents) {
+        return Ok(Some(contents));
+    }
+    match cipher {
+        Some(c) => c
+            .decrypt(&contents)
+            .map(Some)
+            .map_err(|e| format!("Unable to decrypt {path}: {e}")),
+        None => Err(format!(
+            "{path} is encrypted, but no passphrase or key file is configured"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let cipher = Cipher::new(b"hunter2".to_vec());
+        let first = cipher.encrypt(b"cookies");
+        let second = cipher.encrypt(b"more cookies");
+        assert!(is_encrypted(&first));
+        assert_ne!(&first[MAGIC.len()..], b"cookies");
+        // Same salt, so only one key was derived, but fresh nonces
+        assert_eq!(
+            first[..MAGIC.len() + SALT_LEN],
+            second[..MAGIC.len() + SALT_LEN]
+        );
+        assert_ne!(
+            first[MAGIC.len() + SALT_LEN..],
+            second[MAGIC.len() + SALT_LEN..]
+        );
+        assert_eq!(cipher.keys.lock().unwrap().len(), 1);
+        assert_eq!(cipher.decrypt(&first).unwrap(), b"cookies");
+        assert_eq!(cipher.decrypt(&second).unwrap(), b"more cookies");
+
+        // A new cipher with the same passphrase, like after a restart
+        let restarted = Cipher::new(b"hunter2".to_vec());
+        assert_eq!(restarted.decrypt(&first).unwrap(), b"cookies");
+    }
+
+    #[test]
+    fn wrong_passphrase_or_damage() {
+        let encrypted = Cipher::new(b"hunter2".to_vec()).encrypt(b"cookies");
+        assert!(Cipher::new(b"hunter3".to_vec())
+            .decrypt(&encrypted)
+            .is_err());
+
+        let cipher = Cipher::new(b"hunter2".to_vec());
+        let mut damaged = encrypted.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        assert!(cipher.decrypt(&damaged).is_err());
+        assert!(cipher.decrypt(&encrypted[..MAGIC.len() + 4]).is_err());
+        assert!(cipher.decrypt(b"[]").is_err());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("holly-secrets-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let cipher = Cipher::new(b"hunter2".to_vec());
+
+        save(path, b"[]", None).unwrap();
+        assert_eq!(load(path, Some(&cipher)).unwrap().unwrap(), b"[]");
+        save(path, b"[1]", Some(&cipher)).unwrap();
+        assert_eq!(load(path, Some(&cipher)).unwrap().unwrap(), b"[1]");
+        assert!(load(path, None).is_err());
+        std::fs::remove_file(path).unwrap();
+        assert!(load(path, Some(&cipher)).unwrap().is_none());
+    }
+}
@@ -0,0 +1,324 @@
+// Jackson Coxson
+// Locators for every element Holly looks for on Messenger.
+// Facebook renames its obfuscated classes with every deploy, so these live in
+// a TOML file instead of the code. Each element has a list of XPath locators
+// that are tried in order. Anything missing from the file falls back to the
+// built-in copy in src/selectors.toml.
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use thirtyfour::{extensions::query::ElementQuery, prelude::*};
+
+const DEFAULT_SELECTORS: &str = include_str!("selectors.toml");
+
+/// What Holly can't do in the browser while any of these elements has no locators
+const FEATURES: &[(&str, &[&str])] = &[
+    ("message ids", &["message_id"]),
+    ("message timestamps", &["message_time"]),
+    ("from_bot on Holly's own messages", &["own_message"]),
+    ("reply_to on inbound replies", &["reply_quote"]),
+    ("sticker attachments", &["attachment_sticker"]),
+    ("GIF attachments", &["attachment_gif"]),
+    ("image attachments", &["attachment_image"]),
+    ("video attachments", &["attachment_video"]),
+    ("audio attachments", &["attachment_audio"]),
+    ("file attachments", &["attachment_file"]),
+    ("quoted replies", &["reply_button"]),
+    ("inbound reactions", &["reaction"]),
+    ("react command", &["react_button", "reaction_option"]),
+    ("reactions outside the quick picks", &["reaction_more"]),
+    ("edit detection", &["edited_marker"]),
+    ("unsend detection", &["unsent_marker"]),
+];
+
+/// Ordered XPath locators for one element
+pub type Locator = Vec<String>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Selectors {
+    pub version: u32,
+    pub chats_grid: Locator,
+    pub chat_row: Locator,
+    pub chat_link: Locator,
+    pub unread_marker: Locator,
+    pub conversation_grid: Locator,
+    pub message_row: Locator,
+    pub message_body: Locator,
+    pub sender_avatar: Locator,
+    pub emoji: Locator,
+    pub message_id: Locator,
+    pub message_time: Locator,
+    pub own_message: Locator,
+    pub reply_quote: Locator,
+    pub edited_marker: Locator,
+    pub unsent_marker: Locator,
+    pub attachment_sticker: Locator,
+    pub attachment_gif: Locator,
+    pub attachment_image: Locator,
+    pub attachment_video: Locator,
+    pub attachment_audio: Locator,
+    pub attachment_file: Locator,
+    pub textbox: Locator,
+    pub reply_button: Locator,
+    pub reaction: Locator,
+    pub react_button: Locator,
+    pub reaction_option: Locator,
+    pub reaction_more: Locator,
+    pub send_button: Locator,
+    pub invalid_file_dialog: Locator,
+    pub upload_failed_dialog: Locator,
+    pub dialog_close: Locator,
+    pub decline_call: Locator,
+    pub login_email: Locator,
+    pub login_password: Locator,
+    pub login_button: Locator,
+    pub logged_in: Locator,
+    pub e2ee_pin_input: Locator,
+    pub two_factor_input: Locator,
+    pub login_code_submit: Locator,
+    pub checkpoint: Locator,
+    pub captcha: Locator,
+}
+
+impl Selectors {
+    /// Loads the selectors file, or the built-in selectors if there isn't one
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(_) => {
+                info!("No selectors file at {path}, using the built-in selectors");
+                return Ok(Self::default());
+            }
+        };
+        let selectors: Self =
+            toml::from_str(&contents).map_err(|e| format!("Invalid selectors file {path}: {e}"))?;
+
+        let builtin = Self::default().version;
+        if selectors.version < builtin {
+            warn!(
+                "Selectors file {path} is version {}, but Holly ships version {builtin}",
+                selectors.version
+            );
+        }
+        Ok(selectors)
+    }
+
+    /// Logs a warning for everything that's turned off by an empty locator list
+    pub fn warn_unavailable(&self) {
+        let json = serde_json::to_value(self).unwrap();
+        for (feature, elements) in FEATURES {
+            let off: Vec<&str> = elements
+                .iter()
+                .copied()
+                .filter(|e| json[e].as_array().is_none_or(Vec::is_empty))
+                .collect();
+            if !off.is_empty() {
+                warn!(
+                    "No {feature} in the browser, {} has no locators",
+                    off.join(", ")
+                );
+            }
+        }
+    }
+}
+
+impl Default for Selectors {
+    fn default() -> Self {
+        // Parsed without `serde(default)` kicking in, since this is where the defaults come from
+        let table: toml::Table = toml::from_str(DEFAULT_SELECTORS).unwrap();
+        Self {
+            version: table["version"].as_integer().unwrap() as u32,
+            chats_grid: builtin(&table, "chats_grid"),
+            chat_row: builtin(&table, "chat_row"),
+            chat_link: builtin(&table, "chat_link"),
+            unread_marker: builtin(&table, "unread_marker"),
+            conversation_grid: builtin(&table, "conversation_grid"),
+            message_row: builtin(&table, "message_row"),
+            message_body: builtin(&table, "message_body"),
+            sender_avatar: builtin(&table, "sender_avatar"),
+            emoji: builtin(&table, "emoji"),
+            message_id: builtin(&table, "message_id"),
+            message_time: builtin(&table, "message_time"),
+            own_message: builtin(&table, "own_message"),
+            reply_quote: builtin(&table, "reply_quote"),
+            edited_marker: builtin(&table, "edited_marker"),
+            unsent_marker: builtin(&table, "unsent_marker"),
+            attachment_sticker: builtin(&table, "attachment_sticker"),
+            attachment_gif: builtin(&table, "attachment_gif"),
+            attachment_image: builtin(&table, "attachment_image"),
+            attachment_video: builtin(&table, "attachment_video"),
+            attachment_audio: builtin(&table, "attachment_audio"),
+            attachment_file: builtin(&table, "attachment_file"),
+            textbox: builtin(&table, "textbox"),
+            reply_button: builtin(&table, "reply_button"),
+            reaction: builtin(&table, "reaction"),
+            react_button: builtin(&table, "react_button"),
+            reaction_option: builtin(&table, "reaction_option"),
+            reaction_more: builtin(&table, "reaction_more"),
+            send_button: builtin(&table, "send_button"),
+            invalid_file_dialog: builtin(&table, "invalid_file_dialog"),
+            upload_failed_dialog: builtin(&table, "upload_failed_dialog"),
+            dialog_close: builtin(&table, "dialog_close"),
+            decline_call: builtin(&table, "decline_call"),
+            login_email: builtin(&table, "login_email"),
+            login_password: builtin(&table, "login_password"),
+            login_button: builtin(&table, "login_button"),
+            logged_in: builtin(&table, "logged_in"),
+            e2ee_pin_input: builtin(&table, "e2ee_pin_input"),
+            two_factor_input: builtin(&table, "two_factor_input"),
+            login_code_submit: builtin(&table, "login_code_submit"),
+            checkpoint: builtin(&table, "checkpoint"),
+            captcha: builtin(&table, "captcha"),
+        }
+    }
+}
+
+fn builtin(table: &toml::Table, key: &str) -> Locator {
+    table[key]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|l| l.as_str().unwrap().to_string())
+        .collect()
+}
+
+/// The locators for an element, or an error if they're left empty, which turns it off
+pub fn require<'a>(locator: &'a Locator, name: &str) -> Result<&'a Locator, String> {
+    if locator.is_empty() {
+        return Err(format!(
+            "`{name}` has no locators in the selectors file, so this isn't supported yet"
+        ));
+    }
+    Ok(locator)
+}
+
+/// How one critical element fared against the live page
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SelectorCheck {
+    /// The key in the selectors file
+    pub element: String,
+    /// How many elements the locators matched
+    pub matches: usize,
+    /// Whether the match count is healthy.
+    /// The unread marker and sender avatars can legitimately match nothing, so they're always ok.
+    pub ok: bool,
+}
+
+/// Checks each critical locator against the current page
+pub async fn check(
+    driver: &WebDriver,
+    selectors: &Selectors,
+) -> WebDriverResult<Vec<SelectorCheck>> {
+    let chats = driver
+        .locate(&selectors.chats_grid)
+        .nowait()
+        .first_opt()
+        .await?;
+    let conversation = driver
+        .locate(&selectors.conversation_grid)
+        .nowait()
+        .first_opt()
+        .await?;
+
+    let mut res = vec![
+        SelectorCheck::new("chats_grid", usize::from(chats.is_some()), true),
+        SelectorCheck::new(
+            "conversation_grid",
+            usize::from(conversation.is_some()),
+            true,
+        ),
+        SelectorCheck::new(
+            "textbox",
+            driver
+                .locate(&selectors.textbox)
+                .nowait()
+                .all()
+                .await?
+                .len(),
+            true,
+        ),
+    ];
+    res.push(match &conversation {
+        Some(c) => SelectorCheck::new(
+            "message_body",
+            c.locate(&selectors.message_body)
+                .nowait()
+                .all()
+                .await?
+                .len(),
+            true,
+        ),
+        None => SelectorCheck::new("message_body", 0, true),
+    });
+    res.push(match &conversation {
+        Some(c) => SelectorCheck::new(
+            "sender_avatar",
+            c.locate(&selectors.sender_avatar)
+                .nowait()
+                .all()
+                .await?
+                .len(),
+            false,
+        ),
+        None => SelectorCheck::new("sender_avatar", 0, false),
+    });
+    res.push(match &chats {
+        Some(c) => SelectorCheck::new(
+            "unread_marker",
+            c.locate(&selectors.unread_marker)
+                .nowait()
+                .all()
+                .await?
+                .len(),
+            false,
+        ),
+        None => SelectorCheck::new("unread_marker", 0, false),
+    });
+    Ok(res)
+}
+
+impl SelectorCheck {
+    fn new(element: &str, matches: usize, required: bool) -> Self {
+        Self {
+            element: element.to_string(),
+            matches,
+            ok: matches > 0 || !required,
+        }
+    }
+}
+
+/// Queries by `Locator` on anything that can be queried
+pub trait Locate: ElementQueryable {
+    /// Starts a query that tries each of the locators in order.
+    /// Like any query, it waits unless `nowait` is called on it.
+    fn locate(&self, locator: &[String]) -> ElementQuery {
+        let mut locators = locator.iter();
+        let first = locators.next().map(String::as_str).unwrap_or_default();
+        let mut query = self.query(By::XPath(first));
+        for l in locators {
+            query = query.or(By::XPath(l));
+        }
+        query
+    }
+}
+
+impl<T: ElementQueryable + ?Sized> Locate for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn features_name_real_elements() {
+        let json = serde_json::to_value(Selectors::default()).unwrap();
+        for (feature, elements) in FEATURES {
+            for e in *elements {
+                assert!(
+                    json[e].is_array(),
+                    "{feature} needs `{e}`, which isn't an element"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,189 @@
+// Jackson Coxson
+// TCP server that children connect to
+
+use std::{net::SocketAddr, sync::Arc};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{Receiver, Sender},
+        Mutex,
+    },
+};
+
+use crate::{
+    browser::SendError,
+    config::Tcp,
+    framing::{self, FrameDecoder, Framing},
+    protocol::{self, Command, Event, Priority, PROTOCOL_VERSION},
+};
+
+/// Senders for every connected child
+pub type Clients = Arc<Mutex<Vec<Sender<Event>>>>;
+
+/// A command from a child, with a way to tell that child how it went
+#[derive(Serialize, Deserialize)]
+pub struct Request {
+    pub command: Command,
+    pub request_id: Option<String>,
+    pub priority: Priority,
+    /// The child's connection. Gone if the request was read back from disk.
+    #[serde(skip)]
+    pub reply: Option<Sender<Event>>,
+}
+
+/// Accepts children forever, forwarding their commands to `tx`
+pub async fn serve(listener: TcpListener, tcp: Tcp, clients: Clients, tx: Sender<Request>) {
+    let framing = tcp.framing;
+    let max_frame_size = tcp.max_frame_size;
+    loop {
+        if let Ok((stream, addr)) = listener.accept().await {
+            info!("Accepted connection from {:?}", addr);
+
+            let (local_tx, local_rx) = tokio::sync::mpsc::channel::<Event>(100);
+            clients.lock().await.push(local_tx.clone());
+
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                handle_client(
+                    stream,
+                    addr,
+                    framing,
+                    max_frame_size,
+                    local_tx,
+                    local_rx,
+                    tx,
+                )
+                .await;
+            });
+        }
+    }
+}
+
+/// Sends an event to every connected child, dropping the ones that have gone away
+pub fn broadcast(clients: &Clients, event: Event) {
+    let clients = clients.clone();
+    tokio::task::spawn_blocking(move || {
+        clients
+            .blocking_lock()
+            .retain(|sender| sender.blocking_send(event.clone()).is_ok());
+    });
+}
+
+impl Request {
+    /// Reports that the command was carried out
+    pub fn sent(&self) {
+        if let Some(request_id) = &self.request_id {
+            self.answer(Event::Sent {
+                request_id: request_id.clone(),
+            });
+        }
+    }
+
+    /// Reports that the command couldn't be carried out
+    pub fn failed(&self, error: &SendError) {
+        if let Some(request_id) = &self.request_id {
+            self.answer(Event::Failed {
+                request_id: request_id.clone(),
+                error: error.code().to_string(),
+                reason: error.to_string(),
+            });
+        }
+    }
+
+    fn answer(&self, event: Event) {
+        let Some(reply) = &self.reply else {
+            return;
+        };
+        // Never hold up the main loop on a slow child
+        if reply.try_send(event).is_err() {
+            warn!(
+                "Unable to deliver a reply for request {:?}",
+                self.request_id
+            );
+        }
+    }
+}
+
+async fn handle_client(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    framing: Framing,
+    max_frame_size: usize,
+    reply: Sender<Event>,
+    mut events: Receiver<Event>,
+    tx: Sender<Request>,
+) {
+    let mut decoder = FrameDecoder::new(framing, max_frame_size);
+    let mut buf = [0; 4096];
+    // Every connection starts out on v1 until it says hello
+    let mut version = 1;
+    loop {
+        let mut replies = Vec::new();
+        tokio::select! {
+            event = events.recv() => {
+                match event {
+                    Some(event) => replies.push(event),
+                    None => break,
+                }
+            }
+            x = stream.read(&mut buf) => {
+                let x = match x {
+                    Ok(0) | Err(_) => break,
+                    Ok(x) => x,
+                };
+                for frame in decoder.push(&buf[0..x]) {
+                    let command = frame
+                        .map_err(|e| e.to_string())
+                        .and_then(|f| protocol::parse_command(&f));
+                    match command {
+                        Ok(envelope) => match envelope.body {
+                            Command::Hello => {
+                                version = envelope.v.min(PROTOCOL_VERSION);
+                                info!("{:?} is speaking protocol v{}", addr, version);
+                                replies.push(Event::Welcome { version });
+                            }
+                            command => {
+                                let request_id = envelope.request_id;
+                                if let Some(request_id) = &request_id {
+                                    replies.push(Event::Ack { request_id: request_id.clone() });
+                                }
+                                let request = Request {
+                                    command,
+                                    request_id,
+                                    priority: envelope.priority,
+                                    reply: Some(reply.clone()),
+                                };
+                                tx.send(request).await.unwrap();
+                            }
+                        },
+                        Err(reason) => {
+                            warn!("Malformed frame from {:?}: {}", addr, reason);
+                            replies.push(Event::Error { reason });
+                        }
+                    }
+                }
+            }
+        }
+
+        for reply in replies {
+            let Some(reply) = reply.encode(version) else {
+                continue;
+            };
+            if stream
+                .write_all(&framing::encode(framing, &reply))
+                .await
+                .is_err()
+            {
+                return;
+            }
+            if stream.flush().await.is_err() {
+                warn!("Unable to flush message to client");
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,102 @@
+// Dumps localStorage and every IndexedDB database of the page's origin to JSON.
+// Binary values are base64 encoded. Records that can't be represented, like
+// non-extractable CryptoKeys, are skipped and counted.
+var done = arguments[arguments.length - 1];
+var skipped = 0;
+
+function request(r) {
+  return new Promise(function (resolve, reject) {
+    r.onsuccess = function () { resolve(r.result); };
+    r.onerror = function () { reject(r.error); };
+  });
+}
+
+function base64(bytes) {
+  var bin = '';
+  for (var i = 0; i < bytes.length; i++) bin += String.fromCharCode(bytes[i]);
+  return btoa(bin);
+}
+
+function encode(v) {
+  if (v === null || typeof v !== 'object') {
+    if (typeof v === 'bigint' || typeof v === 'function' || typeof v === 'symbol') {
+      throw new Error('unsupported ' + typeof v);
+    }
+    return v;
+  }
+  if (v instanceof Date) return { $t: 'date', v: v.getTime() };
+  if (v instanceof ArrayBuffer) return { $t: 'buffer', b64: base64(new Uint8Array(v)) };
+  if (ArrayBuffer.isView(v)) {
+    return {
+      $t: 'view',
+      type: v.constructor.name,
+      b64: base64(new Uint8Array(v.buffer, v.byteOffset, v.byteLength)),
+    };
+  }
+  if (v instanceof Map) {
+    return { $t: 'map', v: Array.from(v, function (e) { return [encode(e[0]), encode(e[1])]; }) };
+  }
+  if (v instanceof Set) return { $t: 'set', v: Array.from(v, encode) };
+  if (Array.isArray(v)) return v.map(encode);
+  var proto = Object.getPrototypeOf(v);
+  if (proto !== Object.prototype && proto !== null) {
+    throw new Error('unsupported ' + (v.constructor && v.constructor.name));
+  }
+  var o = {};
+  Object.keys(v).forEach(function (k) { o[k] = encode(v[k]); });
+  return o;
+}
+
+async function dumpDatabase(name) {
+  var db = await request(indexedDB.open(name));
+  var out = { name: name, version: db.version, stores: [] };
+  for (var storeName of Array.from(db.objectStoreNames)) {
+    var store = db.transaction(storeName, 'readonly').objectStore(storeName);
+    var dump = {
+      name: storeName,
+      key_path: store.keyPath,
+      auto_increment: store.autoIncrement,
+      indexes: Array.from(store.indexNames).map(function (i) {
+        var index = store.index(i);
+        return { name: i, key_path: index.keyPath, unique: index.unique, multi_entry: index.multiEntry };
+      }),
+      records: [],
+    };
+    // Both requests go out in the same transaction so keys and values line up
+    var pending = [request(store.getAllKeys()), request(store.getAll())];
+    var keys = await pending[0];
+    var values = await pending[1];
+    for (var i = 0; i < keys.length; i++) {
+      try {
+        dump.records.push({ key: encode(keys[i]), value: encode(values[i]) });
+      } catch (e) {
+        skipped++;
+      }
+    }
+    out.stores.push(dump);
+  }
+  db.close();
+  return out;
+}
+
+(async function () {
+  try {
+    var local = {};
+    for (var i = 0; i < localStorage.length; i++) {
+      var key = localStorage.key(i);
+      local[key] = localStorage.getItem(key);
+    }
+    var databases = indexedDB.databases ? await indexedDB.databases() : [];
+    var dumps = [];
+    for (var info of databases) {
+      try {
+        dumps.push(await dumpDatabase(info.name));
+      } catch (e) {
+        skipped++;
+      }
+    }
+    done({ local_storage: local, indexed_db: dumps, skipped: skipped });
+  } catch (e) {
+    done({ error: String(e) });
+  }
+})();
@@ -0,0 +1,100 @@
+// Restores localStorage and IndexedDB from a dump made by storage_dump.js.
+// Missing databases, stores and indexes are created. Records are written over
+// whatever is there. Databases already at a newer version are left alone.
+var data = arguments[0], done = arguments[arguments.length - 1];
+var failed = 0;
+
+function request(r) {
+  return new Promise(function (resolve, reject) {
+    r.onsuccess = function () { resolve(r.result); };
+    r.onerror = function () { reject(r.error); };
+  });
+}
+
+function bytes(b64) {
+  var bin = atob(b64);
+  var out = new Uint8Array(bin.length);
+  for (var i = 0; i < bin.length; i++) out[i] = bin.charCodeAt(i);
+  return out;
+}
+
+function decode(v) {
+  if (v === null || typeof v !== 'object') return v;
+  if (Array.isArray(v)) return v.map(decode);
+  switch (v.$t) {
+    case 'date': return new Date(v.v);
+    case 'buffer': return bytes(v.b64).buffer;
+    case 'view': {
+      var buf = bytes(v.b64).buffer;
+      var View = window[v.type];
+      return v.type === 'DataView' ? new DataView(buf) : new View(buf, 0, buf.byteLength / View.BYTES_PER_ELEMENT);
+    }
+    case 'map': return new Map(v.v.map(function (e) { return [decode(e[0]), decode(e[1])]; }));
+    case 'set': return new Set(v.v.map(decode));
+  }
+  var o = {};
+  Object.keys(v).forEach(function (k) { o[k] = decode(v[k]); });
+  return o;
+}
+
+function loadDatabase(dump) {
+  return new Promise(function (resolve, reject) {
+    var open = indexedDB.open(dump.name, dump.version);
+    open.onupgradeneeded = function () {
+      var db = open.result;
+      dump.stores.forEach(function (s) {
+        var store = db.objectStoreNames.contains(s.name)
+          ? open.transaction.objectStore(s.name)
+          : db.createObjectStore(s.name, { keyPath: s.key_path, autoIncrement: s.auto_increment });
+        s.indexes.forEach(function (i) {
+          if (!store.indexNames.contains(i.name)) {
+            store.createIndex(i.name, i.key_path, { unique: i.unique, multiEntry: i.multi_entry });
+          }
+        });
+      });
+    };
+    open.onsuccess = function () { resolve(open.result); };
+    open.onerror = function () { reject(open.error); };
+  }).then(async function (db) {
+    for (var s of dump.stores) {
+      if (!db.objectStoreNames.contains(s.name)) continue;
+      var tx = db.transaction(s.name, 'readwrite');
+      var store = tx.objectStore(s.name);
+      s.records.forEach(function (r) {
+        try {
+          if (s.key_path === null) {
+            store.put(decode(r.value), decode(r.key));
+          } else {
+            store.put(decode(r.value));
+          }
+        } catch (e) {
+          failed++;
+        }
+      });
+      await new Promise(function (resolve) {
+        tx.oncomplete = resolve;
+        tx.onerror = resolve;
+        tx.onabort = resolve;
+      });
+    }
+    db.close();
+  });
+}
+
+(async function () {
+  try {
+    Object.keys(data.local_storage || {}).forEach(function (k) {
+      localStorage.setItem(k, data.local_storage[k]);
+    });
+    for (var dump of data.indexed_db || []) {
+      try {
+        await loadDatabase(dump);
+      } catch (e) {
+        failed++;
+      }
+    }
+    done({ failed: failed });
+  } catch (e) {
+    done({ error: String(e) });
+  }
+})();
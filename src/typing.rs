@@ -0,0 +1,266 @@
+// Jackson Coxson
+// How messages get typed into the composer.
+// Messages can be inserted all at once, or typed a key at a time at a set
+// speed with some jitter, now and then hitting a neighbouring key and fixing
+// it like a person would. Characters WebDriver can't type, like emoji, are
+// inserted with JS instead. The `[typing]` config sets the defaults, which can
+// be overridden for a chat and again for a single message.
+
+use std::{borrow::Cow, collections::BTreeMap};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use thirtyfour::prelude::*;
+
+/// Rows of a QWERTY keyboard, for picking a key next to the one meant
+const KEYBOARD: [&str; 4] = ["1234567890", "qwertyuiop", "asdfghjkl", "zxcvbnm"];
+
+/// Inserts text into the focused editor in one go, like pasting it
+const INSERT_TEXT: &str =
+    "arguments[0].focus(); return document.execCommand('insertText', false, arguments[1]);";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TypingMode {
+    /// The whole message appears at once
+    Paste,
+    /// One key at a time
+    #[default]
+    Type,
+}
+
+/// Typing settings, as `[typing]` in the config
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Typing {
+    pub mode: TypingMode,
+    /// Typing speed, counting five characters as a word
+    pub wpm: u32,
+    /// How much each delay between keys varies, from 0 (not at all) to 1 (up to double)
+    pub jitter: f64,
+    /// Chance per character of hitting a neighbouring key first and deleting it
+    pub typo_rate: f64,
+    /// Replaces accents, emoji and other scripts with the closest ASCII before sending
+    pub transliterate: bool,
+    /// Overrides by chat id
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub chats: BTreeMap<String, TypingOverride>,
+}
+
+impl Default for Typing {
+    fn default() -> Self {
+        Self {
+            mode: TypingMode::Type,
+            wpm: 600,
+            jitter: 0.3,
+            typo_rate: 0.03,
+            transliterate: false,
+            chats: BTreeMap::new(),
+        }
+    }
+}
+
+/// Changes to the typing settings for a chat or a message. Anything left out is inherited.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TypingOverride {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<TypingMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wpm: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jitter: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub typo_rate: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transliterate: Option<bool>,
+}
+
+/// The settings one message is typed with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Style {
+    pub mode: TypingMode,
+    pub wpm: u32,
+    pub jitter: f64,
+    pub typo_rate: f64,
+    pub transliterate: bool,
+}
+
+impl Typing {
+    /// The settings for a message to the chat, after the chat's and the message's overrides
+    pub fn style(&self, chat_id: &str, message: Option<&TypingOverride>) -> Style {
+        let mut res = Style {
+            mode: self.mode,
+            wpm: self.wpm,
+            jitter: self.jitter,
+            typo_rate: self.typo_rate,
+            transliterate: self.transliterate,
+        };
+        for o in self.chats.get(chat_id).into_iter().chain(message) {
+            res.mode = o.mode.unwrap_or(res.mode);
+            res.wpm = o.wpm.unwrap_or(res.wpm);
+            res.jitter = o.jitter.unwrap_or(res.jitter);
+            res.typo_rate = o.typo_rate.unwrap_or(res.typo_rate);
+            res.transliterate = o.transliterate.unwrap_or(res.transliterate);
+        }
+        res
+    }
+
+    /// Checks the numbers are in range, naming the key that isn't
+    pub fn validate(&self) -> Result<(), String> {
+        let defaults = TypingOverride {
+            mode: None,
+            wpm: Some(self.wpm),
+            jitter: Some(self.jitter),
+            typo_rate: Some(self.typo_rate),
+            transliterate: None,
+        };
+        check(&defaults, "typing")?;
+        for (chat, o) in &self.chats {
+            check(o, &format!("typing.chats.{chat}"))?;
+        }
+        Ok(())
+    }
+}
+
+impl Style {
+    /// The text as it will be typed
+    pub fn prepare<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.transliterate {
+            Cow::Owned(unidecode::unidecode(text))
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+}
+
+impl TypingOverride {
+    /// Checks the numbers are in range
+    pub fn validate(&self) -> Result<(), String> {
+        check(self, "typing")
+    }
+}
+
+fn check(o: &TypingOverride, prefix: &str) -> Result<(), String> {
+    if o.wpm == Some(0) {
+        return Err(format!("`{prefix}.wpm` must be more than 0"));
+    }
+    if o.jitter.is_some_and(|j| !(0.0..=1.0).contains(&j)) {
+        return Err(format!("`{prefix}.jitter` must be between 0 and 1"));
+    }
+    if o.typo_rate.is_some_and(|t| !(0.0..=1.0).contains(&t)) {
+        return Err(format!("`{prefix}.typo_rate` must be between 0 and 1"));
+    }
+    Ok(())
+}
+
+/// Types the text into the element.
+/// Newlines become Shift+Enter, since Enter on its own would send the message early.
+pub async fn type_text(
+    driver: &WebDriver,
+    element: &WebElement,
+    text: &str,
+    style: &Style,
+) -> WebDriverResult<()> {
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            element.send_keys(Key::Shift + Key::Enter).await?;
+            if style.mode == TypingMode::Type {
+                pause(style).await;
+            }
+        }
+        let line = line.trim_end_matches('\r');
+        match style.mode {
+            TypingMode::Paste => paste(driver, element, line).await?,
+            TypingMode::Type => type_line(driver, element, line, style).await?,
+        }
+    }
+    Ok(())
+}
+
+/// Inserts a line all at once
+async fn paste(driver: &WebDriver, element: &WebElement, line: &str) -> WebDriverResult<()> {
+    if line.is_empty() {
+        return Ok(());
+    }
+    let inserted = driver
+        .execute(
+            INSERT_TEXT,
+            vec![element.to_json()?, serde_json::json!(line)],
+        )
+        .await?
+        .convert::<bool>()
+        .unwrap_or(false);
+    if !inserted {
+        // The editor didn't take it, so let WebDriver send it all at once instead
+        element.send_keys(line).await?;
+    }
+    Ok(())
+}
+
+/// Types a line a key at a time
+async fn type_line(
+    driver: &WebDriver,
+    element: &WebElement,
+    line: &str,
+    style: &Style,
+) -> WebDriverResult<()> {
+    for c in line.chars() {
+        if !typeable(c) {
+            paste(driver, element, &c.to_string()).await?;
+            pause(style).await;
+            continue;
+        }
+        let typo = rand::thread_rng().gen_bool(style.typo_rate.clamp(0.0, 1.0));
+        if let Some(wrong) = typo.then(|| neighbour(c)).flatten() {
+            element.send_keys(wrong.to_string()).await?;
+            pause(style).await;
+            element.send_keys(Key::Backspace + "").await?;
+            pause(style).await;
+        }
+        element.send_keys(c.to_string()).await?;
+        pause(style).await;
+    }
+    Ok(())
+}
+
+/// Whether WebDriver can send the character as a key.
+/// Drivers reject characters outside the Basic Multilingual Plane, which is where most emoji are,
+/// and the joiners and variation selectors that build emoji sequences don't type reliably.
+fn typeable(c: char) -> bool {
+    (c as u32) <= 0xFFFF && !matches!(c, '\u{200D}' | '\u{FE00}'..='\u{FE0F}')
+}
+
+/// Waits as long as a key takes at the style's speed
+async fn pause(style: &Style) {
+    let base = 60_000.0 / (style.wpm.max(1) as f64 * 5.0);
+    let jitter = style.jitter.clamp(0.0, 1.0);
+    let ms = base * rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter);
+    tokio::time::sleep(std::time::Duration::from_millis(ms as u64)).await;
+}
+
+/// A random key next to this one on a QWERTY keyboard, in the same case
+fn neighbour(c: char) -> Option<char> {
+    let lower = c.to_ascii_lowercase();
+    let (row, col) = KEYBOARD
+        .iter()
+        .enumerate()
+        .find_map(|(r, keys)| keys.find(lower).map(|i| (r, i)))?;
+
+    let mut keys = Vec::new();
+    for row in &KEYBOARD[row.saturating_sub(1)..(row + 2).min(KEYBOARD.len())] {
+        let chars: Vec<char> = row.chars().collect();
+        for i in col.saturating_sub(1)..=col + 1 {
+            if let Some(&k) = chars.get(i) {
+                if k != lower {
+                    keys.push(k);
+                }
+            }
+        }
+    }
+    let k = keys[rand::thread_rng().gen_range(0..keys.len())];
+    Some(if c.is_ascii_uppercase() {
+        k.to_ascii_uppercase()
+    } else {
+        k
+    })
+}
@@ -0,0 +1,21 @@
+// Jackson Coxson
+// Runs the selector fixtures in fixtures/ against a real browser.
+// Needs a WebDriver and a config file pointing at it (config.toml, or the
+// HOLLY_CONFIG_PATH env var), so it only runs with `cargo test -- --ignored`.
+
+use std::process::Command;
+
+#[test]
+#[ignore = "needs a browser and a WebDriver"]
+fn fixtures() {
+    let status = Command::new(env!("CARGO_BIN_EXE_holly"))
+        .arg("fixtures")
+        .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .status()
+        .expect("unable to run holly");
+    assert!(
+        status.success(),
+        "some fixtures failed, see the output above"
+    );
+}